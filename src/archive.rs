@@ -0,0 +1,237 @@
+use crate::wal::Wal;
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+// Limits are combined: an archived log is kept only while it is within all of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_files: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchivedWal {
+    pub seq: usize,
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PurgeReport {
+    pub removed: Vec<usize>,
+    pub freed_bytes: u64,
+}
+
+// Retired logs kept as `archive/wal_<seq>.log`, where seq is the id of the memtable's WAL
+#[derive(Debug, Clone)]
+pub struct WalArchive {
+    dir: PathBuf,
+    retention: Retention,
+}
+
+impl WalArchive {
+    pub fn new(dir: PathBuf, retention: Retention) -> Self {
+        Self { dir, retention }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    pub fn archive(&self, wal: &Wal) -> Result<PurgeReport> {
+        wal.retire(&self.dir)?;
+        self.purge(&self.retention)
+    }
+
+    pub fn list(&self) -> Result<Vec<ArchivedWal>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut archived = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(seq) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix("wal_")?.strip_suffix(".log"))
+                .and_then(|seq| seq.parse().ok())
+            else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            archived.push(ArchivedWal {
+                seq,
+                path: entry.path(),
+                len: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+        archived.sort_by_key(|wal| wal.seq);
+        Ok(archived)
+    }
+
+    // Removes the oldest logs until the rest fits the given policy
+    pub fn purge(&self, policy: &Retention) -> Result<PurgeReport> {
+        let archived = self.list()?;
+        let now = SystemTime::now();
+
+        let mut kept_files = 0;
+        let mut kept_bytes = 0;
+        let keep = archived
+            .iter()
+            .rev()
+            .take_while(|wal| {
+                let age = now.duration_since(wal.modified).unwrap_or_default();
+                let fits = policy.max_files.is_none_or(|max| kept_files < max)
+                    && policy
+                        .max_bytes
+                        .is_none_or(|max| kept_bytes + wal.len <= max)
+                    && policy.max_age.is_none_or(|max| age <= max);
+                if fits {
+                    kept_files += 1;
+                    kept_bytes += wal.len;
+                }
+                fits
+            })
+            .count();
+
+        self.remove(&archived[..archived.len() - keep])
+    }
+
+    pub fn purge_up_to(&self, seq: usize) -> Result<PurgeReport> {
+        let archived = self.list()?;
+        let end = archived.partition_point(|wal| wal.seq <= seq);
+        self.remove(&archived[..end])
+    }
+
+    fn remove(&self, archived: &[ArchivedWal]) -> Result<PurgeReport> {
+        let mut report = PurgeReport::default();
+        for wal in archived {
+            fs::remove_file(&wal.path)?;
+            report.removed.push(wal.seq);
+            report.freed_bytes += wal.len;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::keyring::{Keyring, random_key};
+    use crate::testutil;
+    use crate::wal::{Durability, RecordKind, WalRecord};
+    use std::fs::File;
+
+    // Writes a log with the given number of records and archives it under the archive's retention
+    fn archive_log(dir: &Path, archive: &WalArchive, id: usize, records: u8) -> PurgeReport {
+        let master = random_key().unwrap();
+        let keyring = Keyring::new(Secret::Key([1; 32]), Some(&master));
+        let wal = Wal::open(dir, id, Durability::Always, &keyring).unwrap();
+        for n in 0..records {
+            wal.append(&WalRecord {
+                kind: RecordKind::Set,
+                timestamp: n as u64,
+                expires_at: None,
+                key: vec![n],
+                sealed_key: false,
+                padded: false,
+                nonce: [n; 12],
+                salt: [0; 16],
+                sealed: vec![n; 64],
+            })
+            .unwrap();
+        }
+        archive.archive(&wal).unwrap()
+    }
+
+    fn seqs(archive: &WalArchive) -> Vec<usize> {
+        archive.list().unwrap().iter().map(|wal| wal.seq).collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_files() {
+        let dir = testutil::temp_dir("archive");
+        let retention = Retention {
+            max_files: Some(2),
+            ..Retention::default()
+        };
+        let archive = WalArchive::new(dir.join("archive"), retention);
+
+        let removed: Vec<_> = (0..5)
+            .map(|id| archive_log(&dir, &archive, id, 1).removed)
+            .collect();
+        assert_eq!(removed, [vec![], vec![], vec![0], vec![1], vec![2]]);
+        assert_eq!(seqs(&archive), [3, 4]);
+        // The live logs were moved, not copied
+        assert!(!dir.join("wal_4.log").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_newest_files_that_fit_in_max_bytes() {
+        let dir = testutil::temp_dir("archive");
+        let archive = WalArchive::new(dir.join("archive"), Retention::default());
+        for (id, records) in [(0, 1), (1, 8), (2, 1), (3, 2), (4, 1)] {
+            archive_log(&dir, &archive, id, records);
+        }
+        assert_eq!(seqs(&archive), [0, 1, 2, 3, 4]);
+        let len = |seq: usize| archive.list().unwrap()[seq].len;
+
+        // The three newest fit, the large one does not and takes the small one before it along
+        let report = archive
+            .purge(&Retention {
+                max_bytes: Some(len(2) + len(3) + len(4) + len(0)),
+                ..Retention::default()
+            })
+            .unwrap();
+        assert_eq!(report.removed, [0, 1]);
+        assert_eq!(seqs(&archive), [2, 3, 4]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_files_past_max_age_along_with_other_limits() {
+        let dir = testutil::temp_dir("archive");
+        let archive = WalArchive::new(dir.join("archive"), Retention::default());
+        for id in 0..4 {
+            archive_log(&dir, &archive, id, 1);
+        }
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        for wal in &archive.list().unwrap()[..2] {
+            File::options()
+                .write(true)
+                .open(&wal.path)
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+
+        let report = archive
+            .purge(&Retention {
+                max_files: Some(3),
+                max_age: Some(Duration::from_secs(60)),
+                ..Retention::default()
+            })
+            .unwrap();
+        assert_eq!(report.removed, [0, 1]);
+        assert_eq!(seqs(&archive), [2, 3]);
+
+        let report = archive.purge_up_to(2).unwrap();
+        assert_eq!(report.removed, [2]);
+        assert_eq!(seqs(&archive), [3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
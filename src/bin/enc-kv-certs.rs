@@ -0,0 +1,25 @@
+use anyhow::Result;
+use enc_kv_store::tls;
+use std::env;
+use std::path::PathBuf;
+
+// enc-kv-certs <dir> [server names...], names default to localhost and 127.0.0.1
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some((dir, names)) = args.split_first() else {
+        anyhow::bail!("usage: enc-kv-certs <dir> [server names...]");
+    };
+    let names = if names.is_empty() {
+        vec!["localhost".to_string(), "127.0.0.1".to_string()]
+    } else {
+        names.to_vec()
+    };
+
+    let dir = PathBuf::from(dir);
+    tls::generate_test_certs(&dir, &names)?;
+    println!(
+        "wrote ca.pem, server.pem/.key and client.pem/.key to {}",
+        dir.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use enc_kv_store::keyring;
+use enc_kv_store::server;
+use enc_kv_store::store::{KvStore, Options};
+use enc_kv_store::tls::{self, TlsIdentity};
+use std::env;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Parser)]
+#[command(
+    name = "enc-kv-server",
+    about = "Serves a store over RESP and the native protocol"
+)]
+struct Cli {
+    /// Data directory, the current directory by default
+    #[arg(long)]
+    dir: Option<PathBuf>,
+
+    /// The password, better passed through the environment than on the command line
+    #[arg(long, env = "ENC_KV_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// File holding a 32-byte key, which unlocks the store alone or together with the password
+    #[arg(long, env = "ENC_KV_KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Address for RESP clients
+    #[arg(long, default_value = "127.0.0.1:6379")]
+    addr: String,
+
+    /// Address for the native protocol
+    #[arg(long, default_value = "127.0.0.1:7379")]
+    native_addr: String,
+
+    /// Require mutual TLS, with ca.pem, server.pem and server.key from this directory
+    #[arg(long)]
+    tls: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    // Note: no fallback password, a server started without one would serve a store anyone opens
+    let secret = keyring::secret(cli.password, cli.key_file.as_deref())?.ok_or_else(|| {
+        anyhow!("a password (--password or ENC_KV_PASSWORD) or a --key-file is needed")
+    })?;
+    let dir = match cli.dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+
+    let tls = cli
+        .tls
+        .map(|dir| tls::server_config(&TlsIdentity::in_dir(&dir, "server")))
+        .transpose()?;
+    let store: Arc<KvStore<Vec<u8>>> = KvStore::open(dir, Options::with_secret(secret))?;
+
+    let native = TcpListener::bind(&cli.native_addr)?;
+    println!("native protocol on {}", native.local_addr()?);
+    let native_store = store.clone();
+    let native_tls = tls.clone();
+    thread::spawn(move || server::serve_native(native_store, native, native_tls));
+
+    let listener = TcpListener::bind(&cli.addr)?;
+    println!(
+        "RESP on {}{}",
+        listener.local_addr()?,
+        if tls.is_some() { " (mutual TLS)" } else { "" }
+    );
+    server::serve(store, listener, tls)
+}
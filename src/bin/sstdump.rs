@@ -0,0 +1,147 @@
+use anyhow::Result;
+use clap::Parser;
+use enc_kv_store::blob::BlobStore;
+use enc_kv_store::encryption::DefaultDecrypter;
+use enc_kv_store::header;
+use enc_kv_store::keyring::{self, FileKey};
+use enc_kv_store::repl::display_bytes;
+use enc_kv_store::segment::{self, EntryScan, SegmentFile};
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Parser)]
+#[command(name = "sstdump", about = "Prints what is inside a segment file")]
+struct Cli {
+    segment: PathBuf,
+
+    /// Also decrypt and print the values
+    #[arg(long, env = "ENC_KV_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Decrypt with a 32-byte key file, alone or together with the password
+    #[arg(long, env = "ENC_KV_KEY_FILE")]
+    key_file: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut seg_file = File::open(&cli.segment)?;
+    let seg_size = seg_file.metadata()?.len();
+    let footer = SegmentFile::parse_footer(&mut seg_file)?;
+
+    println!("file        {} ({seg_size} bytes)", cli.segment.display());
+    println!("version     {}", footer.version);
+    println!("data        0..{}", footer.idx_offset);
+    println!(
+        "index       {}..{} ({} bytes)",
+        footer.idx_offset,
+        footer.idx_offset.saturating_add(footer.idx_size),
+        footer.idx_size
+    );
+    match &footer.key {
+        FileKey::Salt(salt) => println!(
+            "salt        {}",
+            DefaultDecrypter::encode_salt_string(salt)?.as_str()
+        ),
+        FileKey::Wrapped(_) => println!("key         wrapped at {}", footer.key_offset),
+    }
+    println!("compression {}", footer.compression);
+    if footer.sealed_keys {
+        println!("keys        sealed, entries are ordered by key tag");
+    }
+
+    // Note: the footer is printed first and the rest is reported on, so a garbage footer shows up as such
+    let scan = segment::read_entries(&seg_file, &footer).unwrap_or_else(|err| {
+        println!("data unreadable: {err}");
+        EntryScan::default()
+    });
+    let starts: HashSet<u64> = scan.entries.iter().map(|(offset, _)| *offset).collect();
+
+    println!();
+    match segment::read_index(&seg_file, &footer) {
+        Ok(index) => {
+            println!("index entries ({}):", index.len());
+            for (key, offset) in index {
+                let mark = if starts.contains(&offset) {
+                    ""
+                } else {
+                    "  (not an entry start)"
+                };
+                println!("  {} @ {offset}{mark}", display_bytes(&key));
+            }
+        }
+        Err(err) => println!("index unreadable: {err}"),
+    }
+
+    println!();
+    println!("entries     {}", scan.entries.len());
+    if let (Some((_, first)), Some((_, last))) = (scan.entries.first(), scan.entries.last()) {
+        println!(
+            "key range   {} .. {}",
+            display_bytes(&first.key),
+            display_bytes(&last.key)
+        );
+    }
+
+    // Note: the store header and large values live next to the segment
+    let store_dir = cli.segment.parent().unwrap_or(Path::new("."));
+    let opener = keyring::secret(cli.password, cli.key_file.as_deref())?
+        .map(|secret| -> Result<_> {
+            let keyring = Arc::new(header::keyring(store_dir, &secret)?);
+            let decrypter = keyring.decrypter(&footer.key)?;
+            Ok((decrypter, BlobStore::new(store_dir, keyring)?))
+        })
+        .transpose()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    for (offset, entry) in scan.entries {
+        let nonce: String = entry.nonce.iter().map(|b| format!("{b:02x}")).collect();
+        let mut line = format!(
+            "  @{offset}  {}  nonce {nonce}  sealed {} bytes",
+            display_bytes(&entry.key),
+            entry.sealed.len()
+        );
+        if let Some(expires_at) = entry.expires_at {
+            let state = if expires_at <= now { "expired" } else { "live" };
+            line.push_str(&format!("  expires {expires_at} ({state})"));
+        }
+        if entry.blob {
+            line.push_str("  blob");
+        }
+        if entry.padded {
+            line.push_str("  padded");
+        }
+        if entry.is_tombstone() {
+            line.push_str("  tombstone");
+        }
+        if let Some((decrypter, blobs)) = &opener {
+            // Note: `String` and `Vec<u8>` values share an encoding, so any store's values show up
+            let sealed_key = entry.sealed_key;
+            let opened =
+                segment::open_plain(decrypter, footer.compression, entry).and_then(|plain| {
+                    let key = plain.key.clone();
+                    Ok((key, segment::decode_plain::<Vec<u8>>(blobs, plain)?))
+                });
+            match opened {
+                Ok((key, stored)) => {
+                    if sealed_key {
+                        line.push_str(&format!("  key {}", display_bytes(&key)));
+                    }
+                    if let Some(value) = stored.value {
+                        line.push_str(&format!("  -> {}", display_bytes(&value)));
+                    }
+                }
+                Err(err) => line.push_str(&format!("  -> decryption failed: {err}")),
+            }
+        }
+        println!("{line}");
+    }
+
+    if let Some((offset, err)) = scan.error {
+        println!("entries stop at {offset}: {err}");
+    }
+    Ok(())
+}
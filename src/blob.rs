@@ -0,0 +1,322 @@
+use crate::compression::Compression;
+use crate::encryption::{Decrypter, DefaultDecrypter, DefaultEncrypter, Encrypter};
+use crate::keyring::{FileKey, Keyring, WRAPPED_KEY_LEN};
+use crate::padding::{Padding, unpad};
+use crate::segment::read_exact_at;
+use crate::store::KvError;
+use crate::wal::sync_dir;
+use anyhow::{Error, Result};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+// Blob file layout: `EKVBLB` + u16 version, followed by records `salt: 16 | nonce: 12 | len: u32 | sealed`.
+// A sealed value is bound to its key and location, so a pointer only opens the blob it was written for.
+// Version 2 puts the file's wrapped data key after the version, its records have a zero salt.
+const BLOB_MAGIC: &[u8; 6] = b"EKVBLB";
+const BLOB_VERSION: u16 = 2;
+const BLOB_HEADER_SIZE: u64 = 8;
+const RECORD_HEADER_SIZE: u64 = 32;
+
+// A new blob file is started once the active one grows past this
+const MAX_BLOB_FILE: u64 = 1 << 26;
+
+// Pointer flag: the blob is padded
+const REF_PADDED: u8 = 1;
+
+// What a segment entry holds instead of a large value:
+// `file: u64 | offset: u64 | len: u32 | codec: u8 | flags: u8`. Pointers without the codec byte
+// are to uncompressed blobs, pointers without the flags byte to unpadded ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobRef {
+    pub file: usize,
+    pub offset: u64,
+    pub len: u32,
+    pub compression: Compression,
+    pub padded: bool,
+}
+
+impl BlobRef {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(22);
+        buf.extend_from_slice(&(self.file as u64).to_be_bytes());
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf.extend_from_slice(&self.len.to_be_bytes());
+        buf.push(self.compression.id());
+        buf.push(match self.padded {
+            true => REF_PADDED,
+            false => 0,
+        });
+        buf
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if !(20..=22).contains(&bytes.len()) {
+            return Err(Error::msg("blob: malformed pointer"));
+        }
+        let compression = match bytes.get(20) {
+            Some(id) => Compression::from_id(*id)?,
+            None => Compression::None,
+        };
+        let flags = bytes.get(21).copied().unwrap_or(0);
+        if flags & !REF_PADDED != 0 {
+            return Err(Error::msg("blob: unknown pointer flags"));
+        }
+        Ok(Self {
+            file: u64::from_be_bytes(bytes[0..8].try_into()?) as usize,
+            offset: u64::from_be_bytes(bytes[8..16].try_into()?),
+            len: u32::from_be_bytes(bytes[16..20].try_into()?),
+            compression,
+            padded: flags & REF_PADDED != 0,
+        })
+    }
+
+    // Bytes the record takes up in its file
+    pub fn record_len(&self) -> u64 {
+        RECORD_HEADER_SIZE + self.len as u64
+    }
+}
+
+#[derive(Debug)]
+struct ActiveBlob {
+    id: usize,
+    handle: File,
+    len: u64,
+    encrypter: DefaultEncrypter,
+}
+
+#[derive(Debug)]
+struct BlobWriter {
+    active: Option<ActiveBlob>,
+    next_id: usize,
+}
+
+// Append-only `blob_<id>.blob` files next to the segments, written at flush and compaction
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+    keyring: Arc<Keyring>,
+    writer: Mutex<BlobWriter>,
+    // The data key of every file read so far, `None` for files from before data keys
+    decrypters: Mutex<HashMap<usize, Option<DefaultDecrypter>>>,
+}
+
+impl BlobStore {
+    // Note: existing files are never appended to, the first append starts a new one
+    pub fn new(dir: &Path, keyring: Arc<Keyring>) -> Result<Self> {
+        let next_id = list_blob_ids(dir)?.last().map_or(0, |id| id + 1);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            keyring,
+            writer: Mutex::new(BlobWriter {
+                active: None,
+                next_id,
+            }),
+            decrypters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn append(
+        &self,
+        key: &[u8],
+        plaintext: &[u8],
+        compression: Compression,
+        padding: Padding,
+    ) -> Result<BlobRef> {
+        let mut writer = self.writer.lock().expect("blob writer lock");
+        if writer
+            .active
+            .as_ref()
+            .is_none_or(|active| active.len >= MAX_BLOB_FILE)
+        {
+            let id = writer.next_id;
+            writer.active = Some(self.create(id)?);
+            writer.next_id += 1;
+        }
+        let active = writer.active.as_mut().expect("active blob file");
+
+        let offset = active.len;
+        let mut sealed = compression.compress(plaintext);
+        let padded = padding.pad(&mut sealed)?;
+        let record = seal_record(&active.encrypter, key, active.id, offset, sealed)?;
+        active.handle.write_all(&record)?;
+        active.len += record.len() as u64;
+
+        Ok(BlobRef {
+            file: active.id,
+            offset,
+            len: (record.len() as u64 - RECORD_HEADER_SIZE) as u32,
+            compression,
+            padded,
+        })
+    }
+
+    // Has to happen before a segment pointing at new blobs is written
+    pub fn sync(&self) -> Result<()> {
+        if let Some(active) = &self.writer.lock().expect("blob writer lock").active {
+            active.handle.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn active_id(&self) -> Option<usize> {
+        let writer = self.writer.lock().expect("blob writer lock");
+        writer.active.as_ref().map(|active| active.id)
+    }
+
+    pub fn read(&self, key: &[u8], blob: BlobRef) -> Result<Vec<u8>> {
+        let blob_file = File::open(blob_path(&self.dir, blob.file))?;
+        let mut record = vec![0; blob.record_len() as usize];
+        read_exact_at(&blob_file, &mut record, blob.offset)?;
+
+        let plaintext = self.open_record(key, blob, &mut record)?;
+        match blob.padded {
+            true => blob.compression.decompress(unpad(plaintext)?),
+            false => blob.compression.decompress(plaintext),
+        }
+    }
+
+    // The wrapped data key of a file, `None` for files from before data keys
+    pub fn file_key(&self, id: usize) -> Result<Option<[u8; WRAPPED_KEY_LEN]>> {
+        let blob_file = File::open(blob_path(&self.dir, id))?;
+        let mut header = [0u8; BLOB_HEADER_SIZE as usize];
+        read_exact_at(&blob_file, &mut header, 0)?;
+        if &header[0..6] != BLOB_MAGIC {
+            return Err(Error::msg("blob: not a blob file"));
+        }
+        match u16::from_be_bytes(header[6..8].try_into()?) {
+            1 => Ok(None),
+            2 => {
+                let mut wrapped_key = [0u8; WRAPPED_KEY_LEN];
+                read_exact_at(&blob_file, &mut wrapped_key, BLOB_HEADER_SIZE)?;
+                Ok(Some(wrapped_key))
+            }
+            version => Err(Error::msg(format!("blob: unsupported version {version}"))),
+        }
+    }
+
+    // The plaintext as it was sealed, still compressed and padded
+    fn open_record<'a>(
+        &self,
+        key: &[u8],
+        blob: BlobRef,
+        record: &'a mut [u8],
+    ) -> Result<&'a mut [u8]> {
+        let salt: [u8; 16] = record[0..16].try_into()?;
+        let nonce: [u8; 12] = record[16..28].try_into()?;
+        if u32::from_be_bytes(record[28..32].try_into()?) != blob.len {
+            return Err(Error::msg("blob: record length does not match the pointer"));
+        }
+
+        let decrypter = self.decrypter(blob.file, salt)?;
+        let mut aad = blob_aad(key, blob.file, blob.offset);
+        Ok(decrypter.decrypt(&mut record[RECORD_HEADER_SIZE as usize..], nonce, &mut aad)?)
+    }
+
+    // Note: records of files from before data keys name their salt, the keyring caches those keys
+    fn decrypter(&self, id: usize, salt: [u8; 16]) -> Result<DefaultDecrypter> {
+        let mut decrypters = self.decrypters.lock().expect("blob decrypters lock");
+        let decrypter = match decrypters.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let decrypter = match self.file_key(id)? {
+                    Some(wrapped_key) => {
+                        Some(self.keyring.decrypter(&FileKey::Wrapped(wrapped_key))?)
+                    }
+                    None => None,
+                };
+                entry.insert(decrypter)
+            }
+        };
+        match decrypter {
+            Some(decrypter) => Ok(decrypter.clone()),
+            None => self.keyring.decrypter(&FileKey::Salt(salt)),
+        }
+    }
+
+    // Size of every blob file, the active one included
+    pub fn file_sizes(&self) -> Result<Vec<(usize, u64)>> {
+        list_blob_ids(&self.dir)?
+            .into_iter()
+            .map(|id| Ok((id, fs::metadata(blob_path(&self.dir, id))?.len())))
+            .collect()
+    }
+
+    pub fn remove(&self, id: usize) -> Result<()> {
+        fs::remove_file(blob_path(&self.dir, id))?;
+        self.decrypters
+            .lock()
+            .expect("blob decrypters lock")
+            .remove(&id);
+        Ok(())
+    }
+
+    fn create(&self, id: usize) -> Result<ActiveBlob> {
+        let (encrypter, wrapped_key) = self.keyring.new_data_key()?;
+        let mut handle = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(blob_path(&self.dir, id))?;
+        handle.write_all(BLOB_MAGIC)?;
+        handle.write_all(&BLOB_VERSION.to_be_bytes())?;
+        handle.write_all(&wrapped_key)?;
+        handle.sync_data()?;
+        sync_dir(&self.dir)?;
+        Ok(ActiveBlob {
+            id,
+            handle,
+            len: BLOB_HEADER_SIZE + WRAPPED_KEY_LEN as u64,
+            encrypter,
+        })
+    }
+}
+
+fn seal_record(
+    encrypter: &DefaultEncrypter,
+    key: &[u8],
+    file: usize,
+    offset: u64,
+    mut sealed: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let nonce = encrypter
+        .encrypt(&mut sealed, Some(&blob_aad(key, file, offset)))
+        .map_err(KvError::from)?;
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + sealed.len());
+    record.extend_from_slice(&[0; 16]);
+    record.extend_from_slice(&nonce);
+    record.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+    record.extend_from_slice(&sealed);
+    Ok(record)
+}
+
+fn blob_aad(key: &[u8], file: usize, offset: u64) -> Vec<u8> {
+    let mut aad = key.to_vec();
+    aad.extend_from_slice(&(file as u64).to_be_bytes());
+    aad.extend_from_slice(&offset.to_be_bytes());
+    aad
+}
+
+pub fn blob_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("blob_{id}.blob"))
+}
+
+pub fn list_blob_ids(dir: &Path) -> Result<Vec<usize>> {
+    let mut ids: Vec<usize> = fs::read_dir(dir)?
+        .filter_map(|entry_result| {
+            let file_name = entry_result.ok()?.file_name();
+            file_name
+                .to_str()?
+                .strip_prefix("blob_")?
+                .strip_suffix(".blob")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
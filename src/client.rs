@@ -0,0 +1,328 @@
+use crate::proto::{Request, Response, read_frame};
+use crate::store::{ScanPage, StoreStats};
+use crate::tls::Stream;
+use anyhow::{Result, anyhow};
+use rustls::{ClientConfig, ClientConnection, StreamOwned, pki_types::ServerName};
+use std::{
+    collections::HashMap,
+    io::{BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// Unanswered request bytes a pipeline keeps on the wire, well under a socket buffer
+const PIPELINE_WINDOW: usize = 32 * 1024;
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+    next_id: u32,
+}
+
+// Remote counterpart of `KvStore<Vec<u8>>`, talking to `server::serve_native`
+// Note: calls from several threads are serialized on the one connection
+pub struct Client {
+    conn: Mutex<Connection>,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self::over(Box::new(stream)))
+    }
+
+    // `server_name` has to match a name in the server certificate, see `tls::client_config`
+    pub fn connect_tls(
+        addr: impl ToSocketAddrs,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let tls = ClientConnection::new(config, ServerName::try_from(server_name.to_string())?)?;
+        let mut stream = StreamOwned::new(tls, stream);
+        // Note: finishing the handshake up front surfaces a rejected certificate here, not on the first call
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        let client = Self::over(Box::new(stream));
+        client.ping()?;
+        Ok(client)
+    }
+
+    fn over(stream: Box<dyn Stream>) -> Self {
+        Self {
+            conn: Mutex::new(Connection {
+                stream: BufReader::new(stream),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn ping(&self) -> Result<()> {
+        match self.call(Request::Ping)? {
+            Response::Pong => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.call(Request::Get { key: key.to_vec() })? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.done(Request::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl: None,
+        })
+    }
+
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.done(Request::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl: Some(ttl),
+        })
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.done(Request::Delete { key: key.to_vec() })
+    }
+
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        match self.call(Request::Expire {
+            key: key.to_vec(),
+            ttl,
+        })? {
+            Response::Existed(existed) => Ok(existed),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn scan(&self, after: Option<&[u8]>, limit: usize) -> Result<ScanPage<Vec<u8>>> {
+        match self.call(Request::Scan {
+            after: after.map(<[u8]>::to_vec),
+            limit: limit.min(u32::MAX as usize) as u32,
+        })? {
+            Response::Page { entries, next } => Ok(ScanPage { entries, next }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.done(Request::Flush)
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        match self.call(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    fn done(&self, request: Request) -> Result<()> {
+        match self.call(request)? {
+            Response::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn call(&self, request: Request) -> Result<Response> {
+        let mut responses = self.send_all(vec![request])?;
+        Ok(responses.remove(0))
+    }
+
+    // Writes requests while fewer than PIPELINE_WINDOW bytes of them are unanswered, reading
+    // responses in between, so neither side blocks on a full socket waiting for the other
+    fn send_all(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let mut conn = self.conn.lock().expect("connection lock");
+        let mut ids = Vec::with_capacity(requests.len());
+        let mut pending = HashMap::new();
+        let mut in_flight = 0;
+        let mut answered = HashMap::with_capacity(requests.len());
+        let mut out = Vec::new();
+        for request in &requests {
+            let id = conn.next_id;
+            conn.next_id = conn.next_id.wrapping_add(1);
+            let frame = request.encode(id);
+            while !pending.is_empty() && in_flight + out.len() + frame.len() > PIPELINE_WINDOW {
+                in_flight += write_out(&mut conn, &mut out)?;
+                let (id, response) = read_response(&mut conn, &pending)?;
+                in_flight -= pending.remove(&id).expect("pending request");
+                answered.insert(id, response);
+            }
+            out.extend_from_slice(&frame);
+            pending.insert(id, frame.len());
+            ids.push(id);
+        }
+        write_out(&mut conn, &mut out)?;
+        while !pending.is_empty() {
+            let (id, response) = read_response(&mut conn, &pending)?;
+            pending.remove(&id);
+            answered.insert(id, response);
+        }
+
+        Ok(ids
+            .into_iter()
+            .map(|id| answered.remove(&id).expect("answered request"))
+            .collect())
+    }
+}
+
+// Returns how many bytes went out
+fn write_out(conn: &mut Connection, out: &mut Vec<u8>) -> Result<usize> {
+    let stream = conn.stream.get_mut();
+    stream.write_all(out)?;
+    stream.flush()?;
+    let written = out.len();
+    out.clear();
+    Ok(written)
+}
+
+fn read_response(conn: &mut Connection, pending: &HashMap<u32, usize>) -> Result<(u32, Response)> {
+    let payload =
+        read_frame(&mut conn.stream)?.ok_or_else(|| anyhow!("connection closed by the server"))?;
+    let (id, response) = Response::decode(&payload)?;
+    if !pending.contains_key(&id) {
+        return Err(match response {
+            Response::Error(message) => anyhow!(message),
+            _ => anyhow!("response for unknown request id {id}"),
+        });
+    }
+    Ok((id, response))
+}
+
+// Queued requests sent together by `execute`, responses come back in the order queued
+// Server side errors stay in the returned responses, as `Response::Error`
+pub struct Pipeline<'a> {
+    client: &'a Client,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    pub fn push(&mut self, request: Request) -> &mut Self {
+        self.requests.push(request);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn execute(self) -> Result<Vec<Response>> {
+        if self.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.client.send_all(self.requests)
+    }
+}
+
+fn unexpected(response: Response) -> anyhow::Error {
+    match response {
+        Response::Error(message) => anyhow!(message),
+        other => anyhow!("unexpected response {other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::server;
+    use crate::store::{KvStore, Options};
+    use crate::testutil;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn served(dir: &std::path::Path) -> Client {
+        let store = KvStore::open(dir, Options::with_secret(Secret::Key([1; 32]))).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server::serve_native(store, listener, None));
+        Client::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn large_pipelines_answer_in_request_order() {
+        let dir = testutil::temp_dir("client");
+        let client = served(&dir);
+        let value = |n: u8| vec![n; 256 * 1024];
+
+        // Note: megabytes each way, which would fill both sockets if nothing was read until the end
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut pipeline = client.pipeline();
+            for n in 0..64u8 {
+                pipeline.push(Request::Put {
+                    key: vec![n],
+                    value: value(n),
+                    ttl: None,
+                });
+                pipeline.push(Request::Get { key: vec![n] });
+            }
+            pipeline.push(Request::Get {
+                key: b"none".to_vec(),
+            });
+            pipeline.push(Request::Ping);
+            let responses = pipeline.execute();
+            tx.send((responses, client)).unwrap();
+        });
+        let (responses, client) = rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("pipeline finished");
+        let responses = responses.unwrap();
+
+        assert_eq!(responses.len(), 130);
+        for (n, pair) in responses[..128].chunks(2).enumerate() {
+            assert!(matches!(pair[0], Response::Done));
+            assert!(matches!(&pair[1], Response::Value(Some(got)) if *got == value(n as u8)));
+        }
+        assert!(matches!(responses[128], Response::Value(None)));
+        assert!(matches!(responses[129], Response::Pong));
+        client.flush().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn calls_after_a_pipeline_keep_their_ids_apart() {
+        let dir = testutil::temp_dir("client");
+        let client = served(&dir);
+        let mut pipeline = client.pipeline();
+        for n in 0..10u8 {
+            pipeline.push(Request::Put {
+                key: vec![n],
+                value: vec![n],
+                ttl: None,
+            });
+        }
+        assert_eq!(pipeline.len(), 10);
+        pipeline.execute().unwrap();
+        assert!(client.pipeline().execute().unwrap().is_empty());
+
+        for n in 0..10u8 {
+            assert_eq!(client.get(&[n]).unwrap(), Some(vec![n]));
+        }
+        let page = client.scan(None, 3).unwrap();
+        assert_eq!(page.entries.len(), 3);
+        assert_eq!(page.next, Some(vec![2]));
+        client.flush().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use crate::store::KvError;
+use anyhow::{Error, Result};
+use std::{fmt, str::FromStr};
+
+// Applied to encoded values before they are sealed, ciphertext does not compress. Segments
+// record the codec their values were written with and blob pointers the codec of their blob,
+// so the setting can change between runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            _ => Err(Error::msg(format!("compression: unknown codec id {id}"))),
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(bytes),
+        }
+    }
+
+    // Note: only ever called on authenticated plaintext, so the stored length can be trusted
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::decompress_size_prepended(bytes)?),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(KvError("unknown compression, expected none or lz4")),
+        }
+    }
+}
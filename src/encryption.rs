@@ -1,17 +1,65 @@
-use std::fmt::Display;
+use std::fmt::{self, Debug, Display};
 
 use anyhow::Result;
 use argon2::PasswordHasher;
 use argon2::password_hash::Error as ArgonError;
 use argon2::password_hash::rand_core::OsRng;
 use argon2::{
-    Argon2,
+    Algorithm, Argon2, Params, Version,
     password_hash::{Output, SaltString},
 };
 use rand::TryRngCore;
 use ring::aead::BoundKey;
 use ring::aead::{self, NonceSequence};
 use ring::error::Unspecified;
+use ring::hmac;
+
+const KEY_TAG_CONTEXT: &[u8] = b"enc-kv-store key tag";
+const KEY_CHECK_CONTEXT: &[u8] = b"enc-kv-store key check";
+
+pub const SECRET_KEY_LEN: usize = 32;
+
+// What unlocks a store: a passphrase stretched with Argon2, a raw key used as the AES key as is,
+// or both, with the key going into Argon2 as its secret
+#[derive(Clone, PartialEq, Eq)]
+pub enum Secret {
+    Passphrase(String),
+    Key([u8; SECRET_KEY_LEN]),
+    PassphraseAndKey(String, [u8; SECRET_KEY_LEN]),
+}
+
+// Note: only the kind shows, `{:?}` of anything holding a secret ends up in logs
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Secret::Passphrase(_) => "Passphrase",
+            Secret::Key(_) => "Key",
+            Secret::PassphraseAndKey(..) => "PassphraseAndKey",
+        };
+        f.debug_tuple(kind).finish_non_exhaustive()
+    }
+}
+
+impl Secret {
+    fn derive_key(&self, salt: &SaltString) -> Result<Output, KeyGenError> {
+        let (passphrase, argon2) = match self {
+            Secret::Key(key) => return Ok(Output::new(key)?),
+            Secret::Passphrase(passphrase) => (passphrase, Argon2::default()),
+            Secret::PassphraseAndKey(passphrase, key) => (
+                passphrase,
+                Argon2::new_with_secret(
+                    key,
+                    Algorithm::default(),
+                    Version::default(),
+                    Params::default(),
+                )
+                .map_err(ArgonError::from)?,
+            ),
+        };
+        let key = argon2.hash_password(passphrase.as_bytes(), salt.as_salt())?;
+        key.hash.ok_or(KeyGenError::HashMissing)
+    }
+}
 
 pub trait Encrypter {
     fn encrypt(
@@ -21,27 +69,59 @@ pub trait Encrypter {
     ) -> Result<[u8; 12], EncryptError>;
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct DefaultEncrypter {
-    pub password: String,
     pub key: Output,
-    salt: SaltString,
+    // `None` for a key that was not derived from a password
+    salt: Option<SaltString>,
+}
+
+// Note: `Output` prints the key itself
+impl Debug for DefaultEncrypter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultEncrypter")
+            .field("salt", &self.salt)
+            .finish_non_exhaustive()
+    }
 }
 
 impl DefaultEncrypter {
     pub fn new(password: String) -> Result<Self, KeyGenError> {
+        Self::from_secret(&Secret::Passphrase(password))
+    }
+
+    // Note: a raw key skips Argon2 and has no salt
+    pub fn from_secret(secret: &Secret) -> Result<Self, KeyGenError> {
+        if let Secret::Key(key) = secret {
+            return Self::from_key(key);
+        }
         let salt = SaltString::generate(&mut OsRng);
-        let key = Argon2::default().hash_password(password.as_bytes(), salt.as_salt())?;
-        let key_output = key.hash.ok_or(KeyGenError::HashMissing)?;
         Ok(Self {
-            key: key_output.to_owned(),
-            password,
-            salt: salt.to_owned(),
+            key: secret.derive_key(&salt)?,
+            salt: Some(salt),
+        })
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<Self, KeyGenError> {
+        Ok(Self {
+            key: Output::new(key)?,
+            salt: None,
         })
     }
 
     pub fn get_salt_bytes<'a>(&self, buf: &'a mut [u8; 16]) -> Result<&'a [u8]> {
-        Ok(self.salt.decode_b64(buf)?)
+        let salt = self.salt.as_ref().ok_or(KeyGenError::SaltMissing)?;
+        Ok(salt.decode_b64(buf)?)
+    }
+
+    pub fn key_tag(&self, key: &[u8]) -> Vec<u8> {
+        key_tag(self.key.as_bytes(), key)
+    }
+
+    // What the store header keeps to tell a wrong password from missing data
+    pub fn key_check(&self) -> Vec<u8> {
+        let check_key = hmac::Key::new(hmac::HMAC_SHA256, self.key.as_bytes());
+        hmac::sign(&check_key, KEY_CHECK_CONTEXT).as_ref().to_vec()
     }
 }
 
@@ -73,15 +153,37 @@ pub trait Decrypter {
     ) -> Result<&'a mut [u8], DecryptError>;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct DefaultDecrypter {
     key: Output,
 }
 
+impl Debug for DefaultDecrypter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultDecrypter").finish_non_exhaustive()
+    }
+}
+
 impl DefaultDecrypter {
     pub fn new(password: String, salt: SaltString) -> Result<Self> {
-        let key = Self::derive_key(salt, password.clone())?;
-        Ok(Self { key: key })
+        Self::from_secret(&Secret::Passphrase(password), salt)
+    }
+
+    // Note: the salt goes unused for a raw key
+    pub fn from_secret(secret: &Secret, salt: SaltString) -> Result<Self> {
+        Ok(Self {
+            key: secret.derive_key(&salt)?,
+        })
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<Self, KeyGenError> {
+        Ok(Self {
+            key: Output::new(key)?,
+        })
+    }
+
+    pub fn key_bytes(&self) -> &[u8] {
+        self.key.as_bytes()
     }
 
     pub fn encode_salt_string(salt_bytes: &[u8]) -> Result<SaltString> {
@@ -92,12 +194,28 @@ impl DefaultDecrypter {
         Ok(SaltString::from_b64(salt_string)?)
     }
 
-    fn derive_key(salt: SaltString, password: String) -> Result<Output, KeyGenError> {
-        let key = Argon2::default().hash_password(password.as_bytes(), salt.as_salt())?;
-        Ok(key.hash.ok_or(KeyGenError::HashMissing)?)
+    pub fn key_tag(&self, key: &[u8]) -> Vec<u8> {
+        key_tag(self.key.as_bytes(), key)
+    }
+
+    pub fn verify_key_check(&self, check: &[u8]) -> bool {
+        let check_key = hmac::Key::new(hmac::HMAC_SHA256, self.key.as_bytes());
+        hmac::verify(&check_key, KEY_CHECK_CONTEXT, check).is_ok()
     }
 }
 
+// HMAC-SHA256 of a key under a subkey of the AES key, what stands in for the key on disk when
+// keys are encrypted. Equal keys get equal tags under the same salt only.
+fn key_tag(aead_key: &[u8], key: &[u8]) -> Vec<u8> {
+    let tag_key = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, aead_key),
+        KEY_TAG_CONTEXT,
+    );
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, tag_key.as_ref()), key)
+        .as_ref()
+        .to_vec()
+}
+
 impl Decrypter for DefaultDecrypter {
     fn decrypt<'a>(
         &self,
@@ -112,7 +230,7 @@ impl Decrypter for DefaultDecrypter {
         let mut opening_key = aead::OpeningKey::new(u_key, nonce.clone());
         let plaintext = opening_key.open_in_place(aad, enc_bytes)?;
 
-        return Ok(plaintext);
+        Ok(plaintext)
     }
 }
 
@@ -122,6 +240,12 @@ pub struct NoncePlaceholder {
     pub n: [u8; aead::NONCE_LEN],
 }
 
+impl Default for NoncePlaceholder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NoncePlaceholder {
     pub fn new() -> Self {
         let mut nonce = [0u8; aead::NONCE_LEN];
@@ -177,6 +301,7 @@ impl From<Unspecified> for DecryptError {
 pub enum KeyGenError {
     HashError(ArgonError),
     HashMissing,
+    SaltMissing,
 }
 
 impl std::error::Error for KeyGenError {}
@@ -0,0 +1,528 @@
+use crate::encryption::{DefaultDecrypter, DefaultEncrypter, Secret};
+use crate::keyring::{FileKey, Keyring, WRAPPED_KEY_LEN, random_key, unwrap_key, wrap_key};
+use crate::padding::{self, Padding};
+use crate::recovery::logs_since;
+use crate::segment::{self, SegmentFile};
+use crate::store::{WrongPassword, list_segment_ids, segment_path, write_segment};
+use crate::wal::{LEGACY_LOG, read_legacy_log, read_records, sync_dir};
+use anyhow::{Error, Result};
+use std::{
+    fmt,
+    fs::{self, File},
+    path::Path,
+};
+
+pub const HEADER_FILE: &str = "STORE";
+
+// Header layout: `EKVSTO` + u16 version. Version 1 follows with `salt: 16 | check: 32`: the
+// password derives the master key with the salt, the check value is an HMAC of a constant under
+// it and gives nothing else away. Version 2 follows with `slots: u8` and
+// `id: u8 | salt: 16 | wrapped master key` per slot, each slot wraps the same random master key
+// under the key one password derives with the slot's salt. Version 3 adds `kind: u8` after the
+// id, slots for a raw key have a zero salt. Version 4 puts the settings every run has to keep to
+// before the slots: `flags: u8 | padding policy`.
+const HEADER_MAGIC: &[u8; 6] = b"EKVSTO";
+const HEADER_VERSION: u16 = 4;
+const SETTINGS_SIZE: usize = 1 + padding::ENCODED_LEN;
+const DERIVED_HEADER_SIZE: usize = 8 + 16 + 32;
+const V2_SLOT_SIZE: usize = 1 + 16 + WRAPPED_KEY_LEN;
+const SLOT_SIZE: usize = 1 + V2_SLOT_SIZE;
+pub const MAX_KEY_SLOTS: usize = 8;
+
+const SLOT_CONTEXT: &[u8] = b"enc-kv-store master key";
+
+// Settings flag: keys are written as tags, see `Options::encrypt_keys`
+const SETTING_ENCRYPT_KEYS: u8 = 1;
+
+// What a store keeps to once it is turned on, whatever a later run asks for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreSettings {
+    pub encrypt_keys: bool,
+    pub padding: Padding,
+}
+
+impl StoreSettings {
+    fn encode(&self) -> [u8; SETTINGS_SIZE] {
+        let mut bytes = [0u8; SETTINGS_SIZE];
+        if self.encrypt_keys {
+            bytes[0] |= SETTING_ENCRYPT_KEYS;
+        }
+        bytes[1..].copy_from_slice(&self.padding.encode());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes[0] & !SETTING_ENCRYPT_KEYS != 0 {
+            return Err(Error::msg("header: unknown settings flags"));
+        }
+        Ok(Self {
+            encrypt_keys: bytes[0] & SETTING_ENCRYPT_KEYS != 0,
+            padding: Padding::decode(bytes[1..SETTINGS_SIZE].try_into()?)?,
+        })
+    }
+}
+
+// What a slot takes to open, tried only with a secret of the same kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    Passphrase,
+    KeyFile,
+    PassphraseAndKeyFile,
+}
+
+impl SlotKind {
+    pub fn of(secret: &Secret) -> Self {
+        match secret {
+            Secret::Passphrase(_) => SlotKind::Passphrase,
+            Secret::Key(_) => SlotKind::KeyFile,
+            Secret::PassphraseAndKey(..) => SlotKind::PassphraseAndKeyFile,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            SlotKind::Passphrase => 0,
+            SlotKind::KeyFile => 1,
+            SlotKind::PassphraseAndKeyFile => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(SlotKind::Passphrase),
+            1 => Ok(SlotKind::KeyFile),
+            2 => Ok(SlotKind::PassphraseAndKeyFile),
+            _ => Err(Error::msg(format!("header: unknown key slot kind {id}"))),
+        }
+    }
+}
+
+impl fmt::Display for SlotKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlotKind::Passphrase => write!(f, "passphrase"),
+            SlotKind::KeyFile => write!(f, "key-file"),
+            SlotKind::PassphraseAndKeyFile => write!(f, "passphrase+key-file"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    pub id: u8,
+    pub kind: SlotKind,
+    salt: [u8; 16],
+    wrapped_key: [u8; WRAPPED_KEY_LEN],
+}
+
+impl KeySlot {
+    fn seal(id: u8, secret: &Secret, master: &[u8]) -> Result<Self> {
+        let kind = SlotKind::of(secret);
+        let encrypter = DefaultEncrypter::from_secret(secret)?;
+        let mut salt = [0u8; 16];
+        if kind != SlotKind::KeyFile {
+            encrypter.get_salt_bytes(&mut salt)?;
+        }
+        Ok(Self {
+            id,
+            kind,
+            salt,
+            wrapped_key: wrap_key(encrypter.key.as_bytes(), master.try_into()?, SLOT_CONTEXT)?,
+        })
+    }
+
+    fn open(&self, secret: &Secret) -> Result<Vec<u8>> {
+        if SlotKind::of(secret) != self.kind {
+            return Err(WrongPassword.into());
+        }
+        let salt = DefaultDecrypter::encode_salt_string(&self.salt)?;
+        let decrypter = DefaultDecrypter::from_secret(secret, salt)?;
+        unwrap_key(decrypter.key_bytes(), &self.wrapped_key, SLOT_CONTEXT)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreHeader {
+    // Version 1, the password is the only way in
+    Derived {
+        salt: [u8; 16],
+        check: Vec<u8>,
+    },
+    Slots {
+        slots: Vec<KeySlot>,
+        settings: StoreSettings,
+    },
+}
+
+impl StoreHeader {
+    // A header for a new random master key with the secret in slot 0, and the keyring it unlocks
+    pub fn new(secret: &Secret) -> Result<(Self, Keyring)> {
+        let master = random_key()?;
+        let header = StoreHeader::Slots {
+            slots: vec![KeySlot::seal(0, secret, &master)?],
+            settings: StoreSettings::default(),
+        };
+        Ok((header, Keyring::new(secret.clone(), Some(&master))))
+    }
+
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(HEADER_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(path)?;
+        if bytes.len() < 8 || &bytes[0..6] != HEADER_MAGIC {
+            return Err(Error::msg("header: not a store header"));
+        }
+        let header = match u16::from_be_bytes(bytes[6..8].try_into()?) {
+            1 if bytes.len() == DERIVED_HEADER_SIZE => StoreHeader::Derived {
+                salt: bytes[8..24].try_into()?,
+                check: bytes[24..].to_vec(),
+            },
+            // Note: version 2 only had passphrase slots, versions before 4 had no settings
+            version @ (2..=4) => {
+                let (slot_size, settings_size) = match version {
+                    2 => (V2_SLOT_SIZE, 0),
+                    3 => (SLOT_SIZE, 0),
+                    _ => (SLOT_SIZE, SETTINGS_SIZE),
+                };
+                let slots_at = 8 + settings_size + 1;
+                if bytes.len() < slots_at
+                    || bytes.len() != slots_at + bytes[slots_at - 1] as usize * slot_size
+                {
+                    return Err(Error::msg("header: malformed store header"));
+                }
+                let settings = match settings_size {
+                    0 => StoreSettings::default(),
+                    _ => StoreSettings::decode(&bytes[8..8 + settings_size])?,
+                };
+                let slots = bytes[slots_at..]
+                    .chunks_exact(slot_size)
+                    .map(|slot| -> Result<KeySlot> {
+                        let (kind, rest) = match version {
+                            2 => (SlotKind::Passphrase, &slot[1..]),
+                            _ => (SlotKind::from_id(slot[1])?, &slot[2..]),
+                        };
+                        Ok(KeySlot {
+                            id: slot[0],
+                            kind,
+                            salt: rest[0..16].try_into()?,
+                            wrapped_key: rest[16..].try_into()?,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                StoreHeader::Slots { slots, settings }
+            }
+            1 => return Err(Error::msg("header: malformed store header")),
+            version => return Err(Error::msg(format!("header: unsupported version {version}"))),
+        };
+        Ok(Some(header))
+    }
+
+    // Note: written to a temporary file and renamed, a torn header would lock the store
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{HEADER_FILE}.tmp"));
+        write_segment(&tmp_path, &self.encode())?;
+        fs::rename(&tmp_path, dir.join(HEADER_FILE))?;
+        sync_dir(dir)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = HEADER_MAGIC.to_vec();
+        match self {
+            StoreHeader::Derived { salt, check } => {
+                bytes.extend_from_slice(&1u16.to_be_bytes());
+                bytes.extend_from_slice(salt);
+                bytes.extend_from_slice(check);
+            }
+            StoreHeader::Slots { slots, settings } => {
+                bytes.extend_from_slice(&HEADER_VERSION.to_be_bytes());
+                bytes.extend_from_slice(&settings.encode());
+                bytes.push(slots.len() as u8);
+                for slot in slots {
+                    bytes.push(slot.id);
+                    bytes.push(slot.kind.id());
+                    bytes.extend_from_slice(&slot.salt);
+                    bytes.extend_from_slice(&slot.wrapped_key);
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn unlock(&self, secret: &Secret) -> Result<Keyring> {
+        let (_, master) = self.open_slot(secret)?;
+        Ok(Keyring::new(secret.clone(), Some(&master)))
+    }
+
+    pub fn slots(&self) -> Vec<(u8, SlotKind)> {
+        match self {
+            StoreHeader::Derived { .. } => vec![(0, SlotKind::Passphrase)],
+            StoreHeader::Slots { slots, .. } => {
+                slots.iter().map(|slot| (slot.id, slot.kind)).collect()
+            }
+        }
+    }
+
+    pub fn settings(&self) -> StoreSettings {
+        match self {
+            StoreHeader::Derived { .. } => StoreSettings::default(),
+            StoreHeader::Slots { settings, .. } => *settings,
+        }
+    }
+
+    // The header with `settings` in place of the ones it has
+    pub fn with_settings(&self, secret: &Secret, settings: StoreSettings) -> Result<Self> {
+        let (_, _, slots) = self.open_slots(secret)?;
+        Ok(StoreHeader::Slots { slots, settings })
+    }
+
+    // The header with `new` in place of `secret`, in the same slot
+    pub fn with_password(&self, secret: &Secret, new: &Secret) -> Result<(Self, u8)> {
+        let (id, master, mut slots) = self.open_slots(secret)?;
+        for slot in slots.iter_mut().filter(|slot| slot.id == id) {
+            *slot = KeySlot::seal(id, new, &master)?;
+        }
+        let settings = self.settings();
+        Ok((StoreHeader::Slots { slots, settings }, id))
+    }
+
+    // The header with `new` in the first free slot, `secret` has to open one of the others
+    pub fn with_slot_added(&self, secret: &Secret, new: &Secret) -> Result<(Self, u8)> {
+        let (_, master, mut slots) = self.open_slots(secret)?;
+        let id = (0..MAX_KEY_SLOTS as u8)
+            .find(|id| slots.iter().all(|slot| slot.id != *id))
+            .ok_or_else(|| Error::msg("header: every key slot is in use"))?;
+        slots.push(KeySlot::seal(id, new, &master)?);
+        slots.sort_by_key(|slot| slot.id);
+        let settings = self.settings();
+        Ok((StoreHeader::Slots { slots, settings }, id))
+    }
+
+    // Note: any secret of the store can remove any slot but the last one, its own included
+    pub fn without_slot(&self, secret: &Secret, id: u8) -> Result<Self> {
+        self.open_slot(secret)?;
+        let StoreHeader::Slots { slots, settings } = self else {
+            return Err(Error::msg("header: the last key slot cannot be removed"));
+        };
+        if slots.iter().all(|slot| slot.id != id) {
+            return Err(Error::msg(format!("header: no key slot {id}")));
+        }
+        if slots.len() == 1 {
+            return Err(Error::msg("header: the last key slot cannot be removed"));
+        }
+        let slots = slots.iter().filter(|slot| slot.id != id).cloned().collect();
+        Ok(StoreHeader::Slots {
+            slots,
+            settings: *settings,
+        })
+    }
+
+    // The slot the secret opens and the master key. Note: one Argon2 run per slot of its kind
+    // tried, so a wrong password costs as many as there are such slots.
+    fn open_slot(&self, secret: &Secret) -> Result<(u8, Vec<u8>)> {
+        match self {
+            StoreHeader::Derived { salt, check } => {
+                let salt = DefaultDecrypter::encode_salt_string(salt)?;
+                let decrypter = DefaultDecrypter::from_secret(secret, salt)?;
+                match decrypter.verify_key_check(check) {
+                    true => Ok((0, decrypter.key_bytes().to_vec())),
+                    false => Err(WrongPassword.into()),
+                }
+            }
+            StoreHeader::Slots { slots, .. } => slots
+                .iter()
+                .find_map(|slot| Some((slot.id, slot.open(secret).ok()?)))
+                .ok_or_else(|| WrongPassword.into()),
+        }
+    }
+
+    // Like `open_slot`, with the slots to edit. A version 1 header becomes a single slot 0
+    // wrapping the key the password derives, so files sealed under it keep opening.
+    fn open_slots(&self, secret: &Secret) -> Result<(u8, Vec<u8>, Vec<KeySlot>)> {
+        let (id, master) = self.open_slot(secret)?;
+        let slots = match self {
+            StoreHeader::Derived { .. } => vec![KeySlot::seal(0, secret, &master)?],
+            StoreHeader::Slots { slots, .. } => slots.clone(),
+        };
+        Ok((id, master, slots))
+    }
+}
+
+// Checks the secret against the header, for tools that work on a store without opening it.
+// A store without a header only has files from before data keys, and is left without one.
+pub fn keyring(dir: &Path, secret: &Secret) -> Result<Keyring> {
+    if let Some(header) = StoreHeader::read(dir)? {
+        return header.unlock(secret);
+    }
+    let legacy = Keyring::new(secret.clone(), None);
+    if existing_data_opens(dir, &legacy)? == Some(false) {
+        return Err(WrongPassword.into());
+    }
+    Ok(legacy)
+}
+
+// Checks the secret before the store is touched. A store from before headers gets one once an
+// entry it already has authenticates under the secret, an empty store gets one right away.
+pub fn unlock(dir: &Path, secret: &Secret) -> Result<Keyring> {
+    if let Some(header) = StoreHeader::read(dir)? {
+        return header.unlock(secret);
+    }
+    let legacy = Keyring::new(secret.clone(), None);
+    if existing_data_opens(dir, &legacy)? == Some(false) {
+        return Err(WrongPassword.into());
+    }
+    let (header, keyring) = StoreHeader::new(secret)?;
+    header.write(dir)?;
+    Ok(keyring)
+}
+
+// Whether the first entry of any segment or log, the text log included, opens under the secret,
+// `None` when there is nothing to try. Files with a data key cannot be told apart without the
+// header and are skipped.
+// Note: one Argon2 run per salt tried, most stores succeed on the first one.
+fn existing_data_opens(dir: &Path, keyring: &Keyring) -> Result<Option<bool>> {
+    let mut tried = false;
+    for id in list_segment_ids(dir)?.into_iter().rev() {
+        let mut seg_file = File::open(segment_path(dir, id))?;
+        let footer = SegmentFile::parse_footer(&mut seg_file)?;
+        if !matches!(footer.key, FileKey::Salt(_)) {
+            continue;
+        }
+        let Some((_, entry)) = segment::read_entries(&seg_file, &footer)?
+            .entries
+            .into_iter()
+            .next()
+        else {
+            continue;
+        };
+        tried = true;
+        let decrypter = keyring.decrypter(&footer.key)?;
+        if segment::open_plain(&decrypter, footer.compression, entry).is_ok() {
+            return Ok(Some(true));
+        }
+    }
+    for (_, path) in logs_since(dir, 0)?.into_iter().rev() {
+        let recovery = read_records(&fs::read(path)?)?;
+        if recovery.key.is_some() {
+            continue;
+        }
+        let Some(mut record) = recovery.records.into_iter().next() else {
+            continue;
+        };
+        tried = true;
+        if record
+            .open(&keyring.decrypter(&FileKey::Salt(record.salt))?)
+            .is_ok()
+        {
+            return Ok(Some(true));
+        }
+    }
+    let legacy_log = dir.join(LEGACY_LOG);
+    if legacy_log.exists()
+        && let Some(mut record) = read_legacy_log(&fs::read(legacy_log)?)
+            .records
+            .into_iter()
+            .next()
+    {
+        tried = true;
+        if record
+            .open(&keyring.decrypter(&FileKey::Salt(record.salt))?)
+            .is_ok()
+        {
+            return Ok(Some(true));
+        }
+    }
+    Ok(tried.then_some(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn key(n: u8) -> Secret {
+        Secret::Key([n; 32])
+    }
+
+    // A header with a slot for each of `key(0)` to `key(count - 1)`, written to a new directory
+    fn store_with_slots(count: u8) -> (std::path::PathBuf, Keyring) {
+        let dir = testutil::temp_dir("header");
+        let (mut header, keyring) = StoreHeader::new(&key(0)).unwrap();
+        for n in 1..count {
+            let (added, id) = header.with_slot_added(&key(0), &key(n)).unwrap();
+            assert_eq!(id, n);
+            header = added;
+        }
+        header.write(&dir).unwrap();
+        (dir, keyring)
+    }
+
+    fn assert_wrong_password(result: Result<Keyring>) {
+        let err = result.expect_err("wrong password");
+        assert!(err.downcast_ref::<WrongPassword>().is_some(), "{err}");
+    }
+
+    #[test]
+    fn every_slot_unlocks_the_same_master_key() {
+        let (dir, first) = store_with_slots(MAX_KEY_SLOTS as u8);
+        let (_, wrapped) = first.new_data_key().unwrap();
+        for n in 0..MAX_KEY_SLOTS as u8 {
+            let keyring = unlock(&dir, &key(n)).unwrap();
+            assert!(
+                keyring.decrypter(&FileKey::Wrapped(wrapped)).is_ok(),
+                "slot {n}"
+            );
+        }
+        let header = StoreHeader::read(&dir).unwrap().unwrap();
+        assert_eq!(header.slots().len(), MAX_KEY_SLOTS);
+        assert_wrong_password(unlock(&dir, &key(99)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_ninth_slot_is_rejected() {
+        let (dir, _) = store_with_slots(MAX_KEY_SLOTS as u8);
+        let header = StoreHeader::read(&dir).unwrap().unwrap();
+        let err = header.with_slot_added(&key(0), &key(99)).unwrap_err();
+        assert!(
+            err.to_string().contains("every key slot is in use"),
+            "{err}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_removed_slot_no_longer_unlocks() {
+        let (dir, _) = store_with_slots(3);
+        let header = StoreHeader::read(&dir).unwrap().unwrap();
+        // Note: any secret removes any slot, its own included
+        header
+            .without_slot(&key(1), 1)
+            .unwrap()
+            .write(&dir)
+            .unwrap();
+
+        assert_wrong_password(unlock(&dir, &key(1)));
+        assert!(unlock(&dir, &key(0)).is_ok());
+        assert!(unlock(&dir, &key(2)).is_ok());
+        let header = StoreHeader::read(&dir).unwrap().unwrap();
+        let ids: Vec<u8> = header.slots().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0, 2]);
+        // The freed slot is the next one used
+        assert_eq!(header.with_slot_added(&key(0), &key(9)).unwrap().1, 1);
+        assert!(header.without_slot(&key(0), 5).is_err());
+        assert!(header.without_slot(&key(1), 0).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_last_slot_cannot_be_removed() {
+        let (dir, _) = store_with_slots(2);
+        let header = StoreHeader::read(&dir).unwrap().unwrap();
+        let header = header.without_slot(&key(0), 0).unwrap();
+        let err = header.without_slot(&key(1), 1).unwrap_err();
+        assert!(err.to_string().contains("last key slot"), "{err}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use crate::encryption::{
+    Decrypter, DefaultDecrypter, DefaultEncrypter, Encrypter, SECRET_KEY_LEN, Secret,
+};
+use crate::store::KvError;
+use anyhow::{Error, Result};
+use rand::TryRngCore;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fmt, fs,
+    path::Path,
+    sync::Mutex,
+};
+
+pub const DATA_KEY_LEN: usize = 32;
+// `nonce: 12 | sealed data key | tag: 16`
+pub const WRAPPED_KEY_LEN: usize = 12 + DATA_KEY_LEN + 16;
+
+const WRAP_CONTEXT: &[u8] = b"enc-kv-store data key";
+
+// How a file gets its key back. Files from before data keys name the salt of a key derived
+// from the password, newer ones carry a random key of their own, wrapped under the store's
+// master key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileKey {
+    Salt([u8; 16]),
+    Wrapped([u8; WRAPPED_KEY_LEN]),
+}
+
+// The keys of an unlocked store
+pub struct Keyring {
+    // `None` for a store without a header, which can only have files from before data keys
+    master: Option<Vec<u8>>,
+    secret: Secret,
+    // Note: one Argon2 run per salt, files from before data keys share a handful
+    legacy: Mutex<HashMap<[u8; 16], DefaultDecrypter>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("secret", &self.secret)
+            .field("unlocked", &self.master.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keyring {
+    pub fn new(secret: Secret, master: Option<&[u8]>) -> Self {
+        Self {
+            master: master.map(<[u8]>::to_vec),
+            secret,
+            legacy: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn secret(&self) -> &Secret {
+        &self.secret
+    }
+
+    // A random key for a new file, with what the file has to record to get it back
+    pub fn new_data_key(&self) -> Result<(DefaultEncrypter, [u8; WRAPPED_KEY_LEN])> {
+        let data_key = random_key()?;
+        let wrapped = wrap_key(self.master()?, &data_key, WRAP_CONTEXT)?;
+        Ok((DefaultEncrypter::from_key(&data_key)?, wrapped))
+    }
+
+    pub fn decrypter(&self, key: &FileKey) -> Result<DefaultDecrypter> {
+        match key {
+            FileKey::Salt(salt) => {
+                let mut legacy = self.legacy.lock().expect("legacy keys lock");
+                Ok(match legacy.entry(*salt) {
+                    Entry::Occupied(entry) => entry.get().clone(),
+                    Entry::Vacant(entry) => {
+                        let salt = DefaultDecrypter::encode_salt_string(salt)?;
+                        entry
+                            .insert(DefaultDecrypter::from_secret(&self.secret, salt)?)
+                            .clone()
+                    }
+                })
+            }
+            FileKey::Wrapped(wrapped) => {
+                let data_key = unwrap_key(self.master()?, wrapped, WRAP_CONTEXT)?;
+                Ok(DefaultDecrypter::from_key(&data_key)?)
+            }
+        }
+    }
+
+    // For appending to a file, only files with a data key are appended to
+    pub fn encrypter(&self, key: &FileKey) -> Result<DefaultEncrypter> {
+        match key {
+            FileKey::Salt(_) => Err(Error::msg("keyring: file has no data key")),
+            FileKey::Wrapped(wrapped) => {
+                let data_key = unwrap_key(self.master()?, wrapped, WRAP_CONTEXT)?;
+                Ok(DefaultEncrypter::from_key(&data_key)?)
+            }
+        }
+    }
+
+    fn master(&self) -> Result<&[u8]> {
+        self.master
+            .as_deref()
+            .ok_or_else(|| Error::msg("keyring: no store header to unwrap data keys with"))
+    }
+}
+
+// The secret for a passphrase, a key file or both, `None` when neither is given
+pub fn secret(passphrase: Option<String>, key_file: Option<&Path>) -> Result<Option<Secret>> {
+    let key = key_file.map(read_key_file).transpose()?;
+    Ok(match (passphrase, key) {
+        (Some(passphrase), Some(key)) => Some(Secret::PassphraseAndKey(passphrase, key)),
+        (Some(passphrase), None) => Some(Secret::Passphrase(passphrase)),
+        (None, Some(key)) => Some(Secret::Key(key)),
+        (None, None) => None,
+    })
+}
+
+// A key file holds the raw bytes of the key and nothing else
+pub fn read_key_file(path: &Path) -> Result<[u8; SECRET_KEY_LEN]> {
+    let bytes = fs::read(path)?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        Error::msg(format!(
+            "keyring: key file {} holds {} bytes, expected {SECRET_KEY_LEN}",
+            path.display(),
+            bytes.len()
+        ))
+    })
+}
+
+pub fn random_key() -> Result<[u8; DATA_KEY_LEN]> {
+    let mut key = [0u8; DATA_KEY_LEN];
+    rand::rngs::OsRng.try_fill_bytes(&mut key)?;
+    Ok(key)
+}
+
+// Seals a key under another, `context` keeps keys wrapped for one purpose from opening as another
+pub fn wrap_key(
+    wrapping_key: &[u8],
+    key: &[u8; DATA_KEY_LEN],
+    context: &[u8],
+) -> Result<[u8; WRAPPED_KEY_LEN]> {
+    let encrypter = DefaultEncrypter::from_key(wrapping_key)?;
+    let mut sealed = key.to_vec();
+    let nonce = encrypter
+        .encrypt(&mut sealed, Some(context))
+        .map_err(KvError::from)?;
+
+    let mut wrapped = [0u8; WRAPPED_KEY_LEN];
+    wrapped[..12].copy_from_slice(&nonce);
+    wrapped[12..].copy_from_slice(&sealed);
+    Ok(wrapped)
+}
+
+pub fn unwrap_key(
+    wrapping_key: &[u8],
+    wrapped: &[u8; WRAPPED_KEY_LEN],
+    context: &[u8],
+) -> Result<Vec<u8>> {
+    let decrypter = DefaultDecrypter::from_key(wrapping_key)?;
+    let mut sealed = wrapped[12..].to_vec();
+    let key = decrypter
+        .decrypt(
+            &mut sealed,
+            wrapped[..12].try_into()?,
+            &mut context.to_vec(),
+        )
+        .map_err(|_| Error::msg("keyring: key does not unwrap"))?;
+    Ok(key.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{KvStore, Options, WrongPassword};
+    use crate::testutil;
+
+    fn keyring(master: &[u8]) -> Keyring {
+        Keyring::new(Secret::Key([1; 32]), Some(master))
+    }
+
+    #[test]
+    fn data_keys_unwrap_under_their_master_key_only() {
+        let master = random_key().unwrap();
+        let (encrypter, wrapped) = keyring(&master).new_data_key().unwrap();
+        let decrypter = keyring(&master)
+            .decrypter(&FileKey::Wrapped(wrapped))
+            .unwrap();
+        assert_eq!(decrypter.key_bytes(), encrypter.key.as_bytes());
+
+        let other = random_key().unwrap();
+        assert!(
+            keyring(&other)
+                .decrypter(&FileKey::Wrapped(wrapped))
+                .is_err()
+        );
+        for byte in [0, 12, WRAPPED_KEY_LEN - 1] {
+            let mut tampered = wrapped;
+            tampered[byte] ^= 1;
+            assert!(
+                keyring(&master)
+                    .decrypter(&FileKey::Wrapped(tampered))
+                    .is_err()
+            );
+            assert!(
+                keyring(&master)
+                    .encrypter(&FileKey::Wrapped(tampered))
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn every_data_key_is_new() {
+        let keyring = keyring(&random_key().unwrap());
+        let (first, first_wrapped) = keyring.new_data_key().unwrap();
+        let (second, second_wrapped) = keyring.new_data_key().unwrap();
+        assert_ne!(first.key.as_bytes(), second.key.as_bytes());
+        assert_ne!(first_wrapped, second_wrapped);
+    }
+
+    #[test]
+    fn data_keys_need_a_master_key() {
+        let legacy = Keyring::new(Secret::Key([1; 32]), None);
+        assert!(legacy.new_data_key().is_err());
+        let (_, wrapped) = keyring(&random_key().unwrap()).new_data_key().unwrap();
+        assert!(legacy.decrypter(&FileKey::Wrapped(wrapped)).is_err());
+    }
+
+    #[test]
+    fn key_files_of_the_wrong_length_are_errors() {
+        let dir = testutil::temp_dir("keyring");
+        for len in [0, 16, SECRET_KEY_LEN - 1, SECRET_KEY_LEN + 1, 64] {
+            let path = dir.join(format!("key{len}"));
+            fs::write(&path, vec![7; len]).unwrap();
+            let err = read_key_file(&path).unwrap_err();
+            assert!(
+                err.to_string().contains(&format!("holds {len} bytes")),
+                "{err}"
+            );
+            assert!(secret(None, Some(&path)).is_err());
+        }
+        let path = dir.join("key");
+        fs::write(&path, [7; SECRET_KEY_LEN]).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), [7; SECRET_KEY_LEN]);
+        assert!(read_key_file(&dir.join("missing")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn reopens_with(dir: &Path, secret: Secret) -> bool {
+        match KvStore::<Vec<u8>>::open(dir, Options::with_secret(secret)) {
+            Ok(store) => {
+                assert_eq!(store.get(b"k").unwrap(), Some(b"v".to_vec()));
+                true
+            }
+            Err(err) => {
+                assert!(err.downcast_ref::<WrongPassword>().is_some(), "{err}");
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn stores_reopen_only_with_the_secret_they_were_created_with() {
+        let dir = testutil::temp_dir("keyring");
+        let key_file = dir.join("key");
+        fs::write(&key_file, [7; SECRET_KEY_LEN]).unwrap();
+        let pass = || Some("passphrase".to_string());
+
+        let key_only = dir.join("key-only");
+        let both = dir.join("both");
+        for (store_dir, secret) in [
+            (&key_only, secret(None, Some(&key_file))),
+            (&both, secret(pass(), Some(&key_file))),
+        ] {
+            let options = Options::with_secret(secret.unwrap().unwrap());
+            let store = KvStore::<Vec<u8>>::open(store_dir, options).unwrap();
+            store.put(b"k", b"v".to_vec()).unwrap();
+        }
+
+        let key = Secret::Key([7; SECRET_KEY_LEN]);
+        assert!(reopens_with(&key_only, key.clone()));
+        assert!(!reopens_with(&key_only, Secret::Key([8; SECRET_KEY_LEN])));
+        assert!(!reopens_with(
+            &key_only,
+            Secret::Passphrase("passphrase".into())
+        ));
+
+        assert!(reopens_with(
+            &both,
+            secret(pass(), Some(&key_file)).unwrap().unwrap()
+        ));
+        assert!(!reopens_with(&both, key));
+        assert!(!reopens_with(
+            &both,
+            Secret::Passphrase("passphrase".into())
+        ));
+        let wrong = Secret::PassphraseAndKey("passphrase".into(), [8; SECRET_KEY_LEN]);
+        assert!(!reopens_with(&both, wrong));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn debug_output_leaves_out_secrets() {
+        let key = [0xa5; SECRET_KEY_LEN];
+        let secrets = [
+            Secret::Key(key),
+            Secret::Passphrase("hunter2".into()),
+            Secret::PassphraseAndKey("hunter2".into(), key),
+        ];
+        let master = random_key().unwrap();
+        let mut printed = Vec::new();
+        for secret in secrets {
+            let keyring = Keyring::new(secret.clone(), Some(&master));
+            let (encrypter, _) = keyring.new_data_key().unwrap();
+            printed.push(format!("{secret:?}"));
+            printed.push(format!("{keyring:?} {keyring:#?}"));
+            printed.push(format!("{encrypter:?}"));
+            printed.push(format!("{:?}", Options::with_secret(secret)));
+        }
+        printed.push(format!("{:?}", DefaultEncrypter::from_key(&key).unwrap()));
+        printed.push(format!("{:?}", DefaultDecrypter::from_key(&key).unwrap()));
+
+        for text in printed {
+            for leak in ["hunter2", "165", "a5", "A5", "paWl", "master"] {
+                assert!(!text.contains(leak), "{leak} in {text}");
+            }
+        }
+    }
+}
@@ -1,7 +1,27 @@
 pub const MAX_MEMTABLE: usize = 1 << 2;
 pub const FOOTER_SIZE: usize = 1 << 5;
 pub const INDEX_DENSITY: usize = 2;
+pub const BLOB_THRESHOLD: usize = 1 << 10;
 
+pub mod archive;
+pub mod blob;
+pub mod client;
+pub mod compression;
 pub mod encryption;
+pub mod header;
+pub mod keyring;
+pub mod padding;
+pub mod proto;
+pub mod recovery;
+pub mod rekey;
+pub mod repair;
+pub mod repl;
+pub mod resp;
 pub mod segment;
+pub mod server;
 pub mod store;
+#[cfg(test)]
+mod testutil;
+pub mod tls;
+pub mod verify;
+pub mod wal;
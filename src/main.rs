@@ -1,52 +1,496 @@
 use anyhow::Result;
-use enc_kv_store::store::KvStore;
-use once_cell::sync::Lazy;
-use std::path::Path;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use clap::{Args, Parser, Subcommand};
+use enc_kv_store::compression::Compression;
+use enc_kv_store::encryption::Secret;
+use enc_kv_store::keyring;
+use enc_kv_store::padding::Padding;
+use enc_kv_store::rekey::{PasswordChange, RekeyReport};
+use enc_kv_store::repair::{self, RepairReport};
+use enc_kv_store::repl;
+use enc_kv_store::store::{KvStore, Options};
+use enc_kv_store::verify::{self, VerifyReport};
+use serde_json::json;
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::thread;
-use std::{
-    env::{self},
-    fs::{self},
-};
-
-static DEFAULT: Lazy<String> = Lazy::new(|| String::from("default"));
-
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let password = args.get(1).unwrap_or(&DEFAULT);
-    let curr_dir = env::current_dir().expect("invalid curr dir");
-
-    let latest_segment = get_dir_segment_count(curr_dir.as_path()).expect("segment count");
-
-    let hm: Arc<KvStore<String>> = Arc::new(KvStore::new(
-        password.to_owned(),
-        latest_segment,
-        curr_dir.clone(),
-    )?);
-
-    let bg_hm = Arc::clone(&hm);
-    thread::spawn(move || {
-        if let Err(err) = bg_hm.run_bg_thread() {
-            eprintln!("{err}")
-        }
-    });
-    hm.run()
-}
-
-fn get_dir_segment_count(dir_path: &Path) -> Result<usize> {
-    Ok(fs::read_dir(dir_path)?
-        .filter_map(|entry_result| {
-            let entry = entry_result.ok()?;
-            let file_name = entry.file_name();
-            let file_name_str = file_name.to_str()?;
-            if file_name_str.starts_with("segment_") {
-                Some(())
+use std::time::Duration;
+
+// Exit codes: 0 on success, 1 when `get` finds nothing or `verify` finds damage, 2 on errors
+const EXIT_FAILURE: u8 = 1;
+const EXIT_ERROR: u8 = 2;
+
+#[derive(Parser)]
+#[command(name = "enc-kv-store", about = "Encrypted key-value store")]
+struct Cli {
+    /// Data directory, the current directory by default
+    #[arg(long, global = true)]
+    dir: Option<PathBuf>,
+
+    /// The password, `default` when neither it nor a key file is given
+    #[arg(long, global = true, env = "ENC_KV_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// File holding a 32-byte key, which unlocks the store alone or together with the password
+    #[arg(long, global = true, env = "ENC_KV_KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Key arguments are hex encoded, for keys that are not text
+    #[arg(long, global = true)]
+    hex_keys: bool,
+
+    /// Codec for values written to segments: none or lz4
+    #[arg(long, global = true, default_value = "none")]
+    compression: Compression,
+
+    /// Write HMAC tags instead of keys to segments and the log, the keys are encrypted
+    #[arg(long, global = true)]
+    encrypt_keys: bool,
+
+    /// Pads values before sealing so their length is hidden: none, pow2, block:<n> or random:<n>
+    #[arg(long, global = true, default_value = "none")]
+    padding: Padding,
+
+    /// Starts the interactive REPL when left out
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        /// Expire the value after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    Del {
+        key: String,
+    },
+    /// Lists live entries in key order
+    Scan {
+        /// Start after this key
+        #[arg(long)]
+        after: Option<String>,
+        /// Stop after this many entries
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Writes the memtable out to a segment
+    Flush,
+    /// Merges all segments into one, dropping deleted and expired entries
+    Compact,
+    Stats,
+    /// Authenticates every segment and log entry and checks segment layout
+    Verify,
+    /// Rebuilds damaged segments from the entries that still authenticate
+    Repair,
+    /// Puts a new password or key file in place of the current one, in the key slot it opens
+    Passwd {
+        #[command(flatten)]
+        new: NewSecret,
+    },
+    /// Lists the key slots in use and what each takes to open
+    Slots,
+    /// Adds a key slot so another password or key file opens the store too
+    AddSlot {
+        #[command(flatten)]
+        new: NewSecret,
+    },
+    /// Removes a key slot, the last one stays
+    RemoveSlot {
+        slot: u8,
+    },
+    Repl,
+}
+
+// What `passwd` and `add-slot` put in a key slot
+#[derive(Args)]
+struct NewSecret {
+    #[arg(
+        long,
+        env = "ENC_KV_NEW_PASSWORD",
+        hide_env_values = true,
+        required_unless_present = "new_key_file"
+    )]
+    new_password: Option<String>,
+
+    /// File holding a 32-byte key, alone or together with the new password
+    #[arg(long, env = "ENC_KV_NEW_KEY_FILE")]
+    new_key_file: Option<PathBuf>,
+}
+
+impl NewSecret {
+    fn secret(&self) -> Result<Secret> {
+        keyring::secret(self.new_password.clone(), self.new_key_file.as_deref())?
+            .ok_or_else(|| anyhow::anyhow!("a new password or key file is needed"))
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode> {
+    let dir = match cli.dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    let json = cli.json;
+    let secret = keyring::secret(cli.password.clone(), cli.key_file.as_deref())?
+        .unwrap_or_else(|| Secret::Passphrase("default".to_string()));
+    // Note: opening the store replays and truncates logs and fails on a damaged segment,
+    // so these work on the files as they are
+    match cli.command {
+        Some(Command::Verify) => {
+            let report = verify::verify_dir::<Vec<u8>>(&dir, &secret)?;
+            print_verify(&report, json);
+            return Ok(match report.is_ok() {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::from(EXIT_FAILURE),
+            });
+        }
+        Some(Command::Repair) => {
+            let reports = repair::repair_dir::<Vec<u8>>(&dir, &secret)?;
+            print_repair(&reports, json);
+            return Ok(
+                match reports.iter().all(|report| report.quarantined.is_some()) {
+                    true => ExitCode::SUCCESS,
+                    false => ExitCode::from(EXIT_FAILURE),
+                },
+            );
+        }
+        _ => {}
+    }
+
+    let options = Options {
+        compression: cli.compression,
+        encrypt_keys: cli.encrypt_keys,
+        padding: cli.padding,
+        ..Options::with_secret(secret.clone())
+    };
+    let store: Arc<KvStore<Vec<u8>>> = KvStore::open(dir, options)?;
+
+    let code = command(
+        &store,
+        cli.command.unwrap_or(Command::Repl),
+        &secret,
+        json,
+        cli.hex_keys,
+    )?;
+    // Note: waiting for the background flusher, so the next run does not flush the same log again
+    store.drain()?;
+    Ok(code)
+}
+
+fn command(
+    store: &Arc<KvStore<Vec<u8>>>,
+    command: Command,
+    secret: &Secret,
+    json: bool,
+    hex_keys: bool,
+) -> Result<ExitCode> {
+    let key_arg = |key: &str| -> Result<Vec<u8>> {
+        match hex_keys {
+            true => repl::decode_hex(key).map_err(|err| anyhow::anyhow!("{err} in key {key}")),
+            false => Ok(key.as_bytes().to_vec()),
+        }
+    };
+    match command {
+        Command::Repl => repl::run(store)?,
+        Command::Get { key } => {
+            let key = key_arg(&key)?;
+            let value = store.get(&key)?;
+            match (&value, json) {
+                (Some(value), true) => println!("{}", json_entry(&key, value)),
+                (None, true) => {
+                    let mut entry = json_key(&key);
+                    entry["value"] = serde_json::Value::Null;
+                    println!("{entry}");
+                }
+                // Note: the raw bytes, so scripts get back exactly what they stored
+                (Some(value), false) => {
+                    let mut out = io::stdout().lock();
+                    out.write_all(value)?;
+                    out.write_all(b"\n")?;
+                }
+                (None, false) => {}
+            }
+            if value.is_none() {
+                return Ok(ExitCode::from(EXIT_FAILURE));
+            }
+        }
+        Command::Put { key, value, ttl } => {
+            let key = key_arg(&key)?;
+            match ttl {
+                Some(secs) => {
+                    store.put_with_ttl(&key, value.into_bytes(), Duration::from_secs(secs))?
+                }
+                None => store.put(&key, value.into_bytes())?,
+            }
+            if json {
+                println!("{}", json!({ "ok": true }));
+            }
+        }
+        Command::Del { key } => {
+            store.delete(&key_arg(&key)?)?;
+            if json {
+                println!("{}", json!({ "ok": true }));
+            }
+        }
+        Command::Scan { after, limit } => {
+            let mut entries = Vec::new();
+            let mut after = after.as_deref().map(key_arg).transpose()?;
+            let limit = limit.unwrap_or(usize::MAX);
+            while entries.len() < limit {
+                let page = store.scan(after.as_deref(), 1024)?;
+                entries.extend(page.entries);
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+            entries.truncate(limit);
+
+            if json {
+                let entries: Vec<_> = entries
+                    .iter()
+                    .map(|(key, value)| json_entry(key, value))
+                    .collect();
+                println!("{}", serde_json::Value::Array(entries));
+            } else {
+                for (key, value) in entries {
+                    println!(
+                        "{}\t{}",
+                        repl::display_bytes(&key),
+                        repl::display_bytes(&value)
+                    );
+                }
+            }
+        }
+        Command::Flush => {
+            store.flush()?;
+            if json {
+                println!("{}", json!({ "ok": true }));
+            }
+        }
+        Command::Compact => {
+            // Note: flushing first so the memtable takes part as well
+            store.flush()?;
+            let report = store.compact()?;
+            if json {
+                println!(
+                    "{}",
+                    json!({
+                        "segments_in": report.segments_in,
+                        "entries_kept": report.entries_kept,
+                        "entries_dropped": report.entries_dropped,
+                        "blobs_relocated": report.blobs_relocated,
+                        "blob_files_removed": report.blob_files_removed,
+                        "blob_bytes_freed": report.blob_bytes_freed,
+                    })
+                );
             } else {
-                None
+                println!(
+                    "compacted {} segments: kept {} entries, dropped {}",
+                    report.segments_in, report.entries_kept, report.entries_dropped
+                );
+                if report.blobs_relocated > 0 || report.blob_files_removed > 0 {
+                    println!(
+                        "moved {} blobs, removed {} blob files ({} bytes)",
+                        report.blobs_relocated, report.blob_files_removed, report.blob_bytes_freed
+                    );
+                }
+            }
+        }
+        Command::Stats => {
+            let stats = store.stats();
+            if json {
+                println!(
+                    "{}",
+                    json!({
+                        "segments": stats.segments,
+                        "memtable_entries": stats.memtable_entries,
+                        "frozen_memtables": stats.frozen_memtables,
+                        "wal_id": stats.wal_id,
+                        "wal_appended_bytes": stats.wal.appended_bytes,
+                        "wal_synced_bytes": stats.wal.synced_bytes,
+                        "wal_syncs": stats.wal.syncs,
+                    })
+                );
+            } else {
+                println!("segments\t{}", stats.segments);
+                println!("memtable_entries\t{}", stats.memtable_entries);
+                println!("frozen_memtables\t{}", stats.frozen_memtables);
+                println!("wal_id\t{}", stats.wal_id);
+            }
+        }
+        Command::Passwd { new } => {
+            let report = wait_for_rekey(store.change_password(secret, &new.secret()?)?, json)?;
+            print_rekey("password changed", &report, json);
+        }
+        Command::Slots => {
+            let slots = store.key_slots()?;
+            if json {
+                let slots: Vec<_> = slots
+                    .iter()
+                    .map(|(slot, kind)| json!({ "slot": slot, "kind": kind.to_string() }))
+                    .collect();
+                println!("{}", json!({ "slots": slots }));
+            } else {
+                for (slot, kind) in slots {
+                    println!("{slot}\t{kind}");
+                }
+            }
+        }
+        Command::AddSlot { new } => {
+            let report = wait_for_rekey(store.add_key_slot(secret, &new.secret()?)?, json)?;
+            print_rekey("slot added", &report, json);
+        }
+        Command::RemoveSlot { slot } => {
+            store.remove_key_slot(secret, slot)?;
+            if json {
+                println!("{}", json!({ "ok": true }));
             }
-        })
-        .count())
+        }
+        Command::Verify | Command::Repair => unreachable!("runs without opening the store"),
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
-#[cfg(test)]
-mod tests {}
+fn wait_for_rekey(change: PasswordChange, json: bool) -> Result<RekeyReport> {
+    while !change.is_finished() {
+        let progress = change.progress();
+        if !json && progress.files_total > 0 {
+            eprint!(
+                "\rrekeying: {}/{} files",
+                progress.files_done, progress.files_total
+            );
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if !json {
+        eprintln!();
+    }
+    change.wait()
+}
+
+fn print_rekey(what: &str, report: &RekeyReport, json: bool) {
+    if json {
+        println!(
+            "{}",
+            json!({
+                "slot": report.slot,
+                "segments": report.segments,
+                "blob_files": report.blob_files,
+                "logs": report.logs,
+                "bytes_written": report.bytes_written,
+            })
+        );
+    } else {
+        println!(
+            "{what}: slot {}, rekeyed {} segments, {} blob files and {} logs ({} bytes)",
+            report.slot, report.segments, report.blob_files, report.logs, report.bytes_written
+        );
+    }
+}
+
+fn print_verify(report: &VerifyReport, json: bool) {
+    if json {
+        let files: Vec<_> = report
+            .files
+            .iter()
+            .map(|file| {
+                json!({
+                    "file": file.path.display().to_string(),
+                    "entries": file.entries,
+                    "problems": file.problems,
+                })
+            })
+            .collect();
+        println!("{}", json!({ "ok": report.is_ok(), "files": files }));
+        return;
+    }
+    for file in &report.files {
+        let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+        let state = if file.is_ok() { "ok" } else { "FAILED" };
+        println!("{name}\t{state}\t{} entries", file.entries);
+        for problem in &file.problems {
+            println!("  {problem}");
+        }
+    }
+    println!(
+        "{} files checked, {} failed",
+        report.files.len(),
+        report.failed()
+    );
+}
+
+fn print_repair(reports: &[RepairReport], json: bool) {
+    if json {
+        let segments: Vec<_> = reports
+            .iter()
+            .map(|report| {
+                json!({
+                    "segment": report.segment,
+                    "kept": report.kept,
+                    "quarantined": report.quarantined.as_ref().map(|path| path.display().to_string()),
+                })
+            })
+            .collect();
+        println!("{}", json!({ "repaired": segments }));
+        return;
+    }
+    if reports.is_empty() {
+        println!("no damaged segments");
+    }
+    for report in reports {
+        match &report.quarantined {
+            Some(path) => println!(
+                "segment_{}.sstable\trebuilt with {} entries, original moved to {}",
+                report.segment,
+                report.kept,
+                path.display()
+            ),
+            None => println!(
+                "segment_{}.sstable\tnothing authenticates, left in place (wrong password?)",
+                report.segment
+            ),
+        }
+    }
+}
+
+// Text keys and values as strings, anything else base64 encoded under `key_base64` or `value_base64`
+fn json_entry(key: &[u8], value: &[u8]) -> serde_json::Value {
+    let mut entry = json_key(key);
+    match std::str::from_utf8(value) {
+        Ok(text) => entry["value"] = json!(text),
+        Err(_) => entry["value_base64"] = json!(BASE64.encode(value)),
+    }
+    entry
+}
+
+fn json_key(key: &[u8]) -> serde_json::Value {
+    match std::str::from_utf8(key) {
+        Ok(text) => json!({ "key": text }),
+        Err(_) => json!({ "key_base64": BASE64.encode(key) }),
+    }
+}
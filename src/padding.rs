@@ -0,0 +1,196 @@
+use crate::store::KvError;
+use anyhow::{Error, Result};
+use std::{fmt, str::FromStr};
+
+// Padded plaintext: `body | zeros | pad_len: u32`, sealed as a whole so the length of the
+// ciphertext only tells which bucket the body fell into
+const TRAILER_LEN: usize = 4;
+// A policy as the store header keeps it: `kind: u8 | size: u64`
+pub const ENCODED_LEN: usize = 9;
+// Largest block or random size a policy may have
+pub const MAX_PADDING: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    // Up to the next power of two
+    PowerOfTwo,
+    // Up to the next multiple of the block size
+    Block(usize),
+    // Between zero and the given number of bytes, chosen at random for every value
+    Random(usize),
+}
+
+impl Padding {
+    // Returns whether the plaintext was padded and has to be stripped after opening
+    pub fn pad(&self, plaintext: &mut Vec<u8>) -> Result<bool, KvError> {
+        if *self == Padding::None {
+            return Ok(false);
+        }
+        let pad_len = self
+            .pad_len(plaintext.len())
+            .ok_or(KvError("padding: value too large to pad"))?;
+        plaintext.resize(plaintext.len() + pad_len as usize, 0);
+        plaintext.extend_from_slice(&pad_len.to_be_bytes());
+        Ok(true)
+    }
+
+    // Zeros to add to a body of `body_len` bytes, None when the padded length would not fit
+    fn pad_len(&self, body_len: usize) -> Option<u32> {
+        let len = body_len.checked_add(TRAILER_LEN)?;
+        let padded_len = match *self {
+            Padding::None => len,
+            Padding::PowerOfTwo => len.checked_next_power_of_two()?,
+            Padding::Block(block) => len.checked_next_multiple_of(block.max(1))?,
+            Padding::Random(max) => len.checked_add(rand::random_range(0..=max))?,
+        };
+        u32::try_from(padded_len - len).ok()
+    }
+
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let (kind, size) = match *self {
+            Padding::None => (0, 0),
+            Padding::PowerOfTwo => (1, 0),
+            Padding::Block(block) => (2, block),
+            Padding::Random(max) => (3, max),
+        };
+        let mut bytes = [0u8; ENCODED_LEN];
+        bytes[0] = kind;
+        bytes[1..].copy_from_slice(&(size as u64).to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; ENCODED_LEN]) -> Result<Self> {
+        let size = u64::from_be_bytes(bytes[1..].try_into()?);
+        let size = match usize::try_from(size) {
+            Ok(size) if size <= MAX_PADDING => size,
+            _ => return Err(Error::msg(format!("padding: size {size} is too large"))),
+        };
+        match bytes[0] {
+            0 => Ok(Padding::None),
+            1 => Ok(Padding::PowerOfTwo),
+            2 if size > 0 => Ok(Padding::Block(size)),
+            3 => Ok(Padding::Random(size)),
+            kind => Err(Error::msg(format!("padding: unknown policy {kind}"))),
+        }
+    }
+}
+
+pub fn unpad(plaintext: &[u8]) -> Result<&[u8]> {
+    let body_len = plaintext
+        .len()
+        .checked_sub(TRAILER_LEN)
+        .and_then(|len| {
+            let pad_len = u32::from_be_bytes(plaintext[len..].try_into().ok()?) as usize;
+            len.checked_sub(pad_len)
+        })
+        .ok_or_else(|| Error::msg("padding: malformed padded value"))?;
+    Ok(&plaintext[..body_len])
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::None => write!(f, "none"),
+            Padding::PowerOfTwo => write!(f, "pow2"),
+            Padding::Block(block) => write!(f, "block:{block}"),
+            Padding::Random(max) => write!(f, "random:{max}"),
+        }
+    }
+}
+
+impl FromStr for Padding {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const EXPECTED: KvError = KvError(
+            "unknown padding, expected none, pow2, block:<n> or random:<n> with n up to 1048576",
+        );
+        let size = |n: &str| match n.parse() {
+            Ok(size) if size <= MAX_PADDING => Ok(size),
+            _ => Err(EXPECTED),
+        };
+        match s.split_once(':') {
+            None if s == "none" => Ok(Padding::None),
+            None if s == "pow2" => Ok(Padding::PowerOfTwo),
+            Some(("block", n)) => match size(n)? {
+                0 => Err(EXPECTED),
+                block => Ok(Padding::Block(block)),
+            },
+            Some(("random", n)) => size(n).map(Padding::Random),
+            _ => Err(EXPECTED),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(padding: Padding, len: usize) -> Vec<u8> {
+        let body: Vec<u8> = (0..len).map(|n| n as u8).collect();
+        let mut padded = body.clone();
+        assert!(padding.pad(&mut padded).unwrap());
+        assert_eq!(unpad(&padded).unwrap(), body.as_slice());
+        padded
+    }
+
+    #[test]
+    fn pad_and_unpad_round_trip() {
+        for len in [0, 1, 3, 4, 5, 12, 13, 60, 61, 64, 1000, 4092, 4093] {
+            assert!(round_trip(Padding::PowerOfTwo, len).len().is_power_of_two());
+            assert_eq!(round_trip(Padding::Block(64), len).len() % 64, 0);
+            assert_eq!(round_trip(Padding::Block(1), len).len(), len + TRAILER_LEN);
+            let random = round_trip(Padding::Random(100), len).len();
+            assert!((len + TRAILER_LEN..=len + TRAILER_LEN + 100).contains(&random));
+        }
+
+        let mut plain = vec![7; 10];
+        assert!(!Padding::None.pad(&mut plain).unwrap());
+        assert_eq!(plain, vec![7; 10]);
+    }
+
+    #[test]
+    fn pad_lengths_that_overflow_are_none() {
+        for padding in [Padding::PowerOfTwo, Padding::Block(3), Padding::Random(8)] {
+            assert_eq!(padding.pad_len(usize::MAX - 2), None, "{padding}");
+        }
+        assert_eq!(Padding::PowerOfTwo.pad_len(usize::MAX / 2), None);
+        assert_eq!(Padding::Block(MAX_PADDING).pad_len(usize::MAX - 8), None);
+        // Past 8 GiB the zeros a power of two adds no longer fit the u32 trailer
+        assert_eq!(
+            Padding::PowerOfTwo.pad_len((1 << 32) + 1),
+            Some(u32::MAX - 4)
+        );
+        assert_eq!(Padding::PowerOfTwo.pad_len((1 << 33) - 3), None);
+        assert_eq!(Padding::PowerOfTwo.pad_len((1 << 33) - 4), Some(0));
+    }
+
+    #[test]
+    fn unpad_rejects_malformed_trailers() {
+        assert!(unpad(&[0, 0, 0]).is_err());
+        assert!(unpad(&[1, 0, 0, 0, 9]).is_err());
+        assert_eq!(unpad(&[1, 2, 0, 0, 0, 1]).unwrap(), &[1]);
+    }
+
+    #[test]
+    fn sizes_are_bounded() {
+        assert!(matches!("block:16".parse(), Ok(Padding::Block(16))));
+        assert!(matches!(
+            format!("random:{MAX_PADDING}").parse(),
+            Ok(Padding::Random(MAX_PADDING))
+        ));
+        for policy in ["block:0", "block:1048577", "random:1048577", "random:-1"] {
+            assert!(policy.parse::<Padding>().is_err(), "{policy}");
+        }
+        assert!(format!("random:{}", usize::MAX).parse::<Padding>().is_err());
+
+        let mut bytes = Padding::Random(1).encode();
+        bytes[1..].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(Padding::decode(&bytes).is_err());
+        for padding in [Padding::Block(MAX_PADDING), Padding::Random(0)] {
+            assert_eq!(Padding::decode(&padding.encode()).unwrap(), padding);
+        }
+    }
+}
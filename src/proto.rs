@@ -0,0 +1,406 @@
+use crate::store::StoreStats;
+use crate::wal::WalStats;
+use anyhow::{Result, anyhow};
+use std::io::{ErrorKind, Read};
+use std::time::Duration;
+
+// Frame: len u32 | request id u32 | tag u8 | body, all integers big endian
+// Responses carry the id of the request they answer, so clients can pipeline
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+const FRAME_HEADER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Ping,
+    Get {
+        key: Vec<u8>,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Expire {
+        key: Vec<u8>,
+        ttl: Duration,
+    },
+    Scan {
+        after: Option<Vec<u8>>,
+        limit: u32,
+    },
+    Flush,
+    Stats,
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    Pong,
+    Done,
+    Value(Option<Vec<u8>>),
+    Existed(bool),
+    Page {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        next: Option<Vec<u8>>,
+    },
+    Stats(StoreStats),
+    Error(String),
+}
+
+impl Request {
+    pub fn encode(&self, id: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        let tag = match self {
+            Request::Ping => 1,
+            Request::Get { key } => {
+                put_bytes(&mut body, key);
+                2
+            }
+            Request::Put { key, value, ttl } => {
+                put_bytes(&mut body, key);
+                put_bytes(&mut body, value);
+                put_u64(
+                    &mut body,
+                    ttl.map_or(0, |ttl| ttl.as_millis().max(1) as u64),
+                );
+                3
+            }
+            Request::Delete { key } => {
+                put_bytes(&mut body, key);
+                4
+            }
+            Request::Expire { key, ttl } => {
+                put_bytes(&mut body, key);
+                put_u64(&mut body, ttl.as_millis() as u64);
+                5
+            }
+            Request::Scan { after, limit } => {
+                put_optional(&mut body, after.as_deref());
+                body.extend_from_slice(&limit.to_be_bytes());
+                6
+            }
+            Request::Flush => 7,
+            Request::Stats => 8,
+        };
+        frame(id, tag, &body)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<(u32, Self)> {
+        let (id, tag, mut body) = split_frame(payload)?;
+        let request = match tag {
+            1 => Request::Ping,
+            2 => Request::Get {
+                key: get_bytes(&mut body)?,
+            },
+            3 => Request::Put {
+                key: get_bytes(&mut body)?,
+                value: get_bytes(&mut body)?,
+                ttl: Some(get_u64(&mut body)?)
+                    .filter(|ttl| *ttl != 0)
+                    .map(Duration::from_millis),
+            },
+            4 => Request::Delete {
+                key: get_bytes(&mut body)?,
+            },
+            5 => Request::Expire {
+                key: get_bytes(&mut body)?,
+                ttl: Duration::from_millis(get_u64(&mut body)?),
+            },
+            6 => Request::Scan {
+                after: get_optional(&mut body)?,
+                limit: get_u32(&mut body)?,
+            },
+            7 => Request::Flush,
+            8 => Request::Stats,
+            _ => return Err(anyhow!("unknown request tag {tag}")),
+        };
+        Ok((id, request))
+    }
+}
+
+impl Response {
+    pub fn encode(&self, id: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        let tag = match self {
+            Response::Pong => 1,
+            Response::Done => 2,
+            Response::Value(value) => {
+                put_optional(&mut body, value.as_deref());
+                3
+            }
+            Response::Existed(existed) => {
+                body.push(*existed as u8);
+                4
+            }
+            Response::Page { entries, next } => {
+                body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (key, value) in entries {
+                    put_bytes(&mut body, key);
+                    put_bytes(&mut body, value);
+                }
+                put_optional(&mut body, next.as_deref());
+                5
+            }
+            Response::Stats(stats) => {
+                for n in [
+                    stats.segments as u64,
+                    stats.memtable_entries as u64,
+                    stats.frozen_memtables as u64,
+                    stats.wal_id as u64,
+                    stats.wal.appended_bytes,
+                    stats.wal.synced_bytes,
+                    stats.wal.syncs,
+                ] {
+                    put_u64(&mut body, n);
+                }
+                6
+            }
+            Response::Error(message) => {
+                put_bytes(&mut body, message.as_bytes());
+                0
+            }
+        };
+        frame(id, tag, &body)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<(u32, Self)> {
+        let (id, tag, mut body) = split_frame(payload)?;
+        let response = match tag {
+            0 => Response::Error(String::from_utf8_lossy(&get_bytes(&mut body)?).into_owned()),
+            1 => Response::Pong,
+            2 => Response::Done,
+            3 => Response::Value(get_optional(&mut body)?),
+            4 => Response::Existed(get_u8(&mut body)? != 0),
+            5 => {
+                let count = get_u32(&mut body)? as usize;
+                let mut entries = Vec::with_capacity(count.min(1024));
+                for _ in 0..count {
+                    entries.push((get_bytes(&mut body)?, get_bytes(&mut body)?));
+                }
+                Response::Page {
+                    entries,
+                    next: get_optional(&mut body)?,
+                }
+            }
+            6 => Response::Stats(StoreStats {
+                segments: get_u64(&mut body)? as usize,
+                memtable_entries: get_u64(&mut body)? as usize,
+                frozen_memtables: get_u64(&mut body)? as usize,
+                wal_id: get_u64(&mut body)? as usize,
+                wal: WalStats {
+                    appended_bytes: get_u64(&mut body)?,
+                    synced_bytes: get_u64(&mut body)?,
+                    syncs: get_u64(&mut body)?,
+                },
+            }),
+            _ => return Err(anyhow!("unknown response tag {tag}")),
+        };
+        Ok((id, response))
+    }
+}
+
+// Payload of the next frame, `None` when the peer closed the stream between frames
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; FRAME_HEADER_SIZE];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME {
+        return Err(anyhow!("frame of {len} bytes exceeds the limit"));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn frame(id: u32, tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + 5 + body.len());
+    frame.extend_from_slice(&((5 + body.len()) as u32).to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.push(tag);
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn split_frame(mut payload: &[u8]) -> Result<(u32, u8, &[u8])> {
+    let id = get_u32(&mut payload)?;
+    let tag = get_u8(&mut payload)?;
+    Ok((id, tag, payload))
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_optional(buf: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            buf.push(1);
+            put_bytes(buf, bytes);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn get_u8(body: &mut &[u8]) -> Result<u8> {
+    let mut n = [0u8; 1];
+    body.read_exact(&mut n)?;
+    Ok(n[0])
+}
+
+fn get_u32(body: &mut &[u8]) -> Result<u32> {
+    let mut n = [0u8; 4];
+    body.read_exact(&mut n)?;
+    Ok(u32::from_be_bytes(n))
+}
+
+fn get_u64(body: &mut &[u8]) -> Result<u64> {
+    let mut n = [0u8; 8];
+    body.read_exact(&mut n)?;
+    Ok(u64::from_be_bytes(n))
+}
+
+fn get_bytes(body: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = get_u32(body)? as usize;
+    if len > body.len() {
+        return Err(anyhow!("field of {len} bytes overruns the frame"));
+    }
+    let (bytes, rest) = body.split_at(len);
+    *body = rest;
+    Ok(bytes.to_vec())
+}
+
+fn get_optional(body: &mut &[u8]) -> Result<Option<Vec<u8>>> {
+    match get_u8(body)? {
+        0 => Ok(None),
+        _ => Ok(Some(get_bytes(body)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(frame: &[u8]) -> Vec<u8> {
+        read_frame(&mut &frame[..]).unwrap().expect("a frame")
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Ping,
+            Request::Get { key: b"k".to_vec() },
+            Request::Put {
+                key: b"k".to_vec(),
+                value: vec![0, 255, 10],
+                ttl: None,
+            },
+            Request::Put {
+                key: Vec::new(),
+                value: Vec::new(),
+                ttl: Some(Duration::from_millis(1500)),
+            },
+            Request::Delete { key: vec![0; 300] },
+            Request::Expire {
+                key: b"k".to_vec(),
+                ttl: Duration::from_secs(7),
+            },
+            Request::Scan {
+                after: None,
+                limit: 10,
+            },
+            Request::Scan {
+                after: Some(b"a".to_vec()),
+                limit: u32::MAX,
+            },
+            Request::Flush,
+            Request::Stats,
+        ];
+        for (id, request) in requests.into_iter().enumerate() {
+            let id = u32::MAX - id as u32;
+            let decoded = Request::decode(&payload(&request.encode(id))).unwrap();
+            assert_eq!(decoded, (id, request));
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let responses = [
+            Response::Pong,
+            Response::Done,
+            Response::Value(None),
+            Response::Value(Some(b"value".to_vec())),
+            Response::Existed(true),
+            Response::Existed(false),
+            Response::Page {
+                entries: vec![(b"a".to_vec(), b"1".to_vec()), (Vec::new(), Vec::new())],
+                next: Some(b"b".to_vec()),
+            },
+            Response::Page {
+                entries: Vec::new(),
+                next: None,
+            },
+            Response::Stats(StoreStats {
+                segments: 1,
+                memtable_entries: 2,
+                frozen_memtables: 3,
+                wal_id: 4,
+                wal: WalStats {
+                    appended_bytes: 5,
+                    synced_bytes: 6,
+                    syncs: 7,
+                },
+            }),
+            Response::Error("ERR broken".to_string()),
+        ];
+        for (id, response) in responses.into_iter().enumerate() {
+            let frame = response.encode(id as u32);
+            let (decoded_id, decoded) = Response::decode(&payload(&frame)).unwrap();
+            assert_eq!(decoded_id, id as u32);
+            assert_eq!(decoded.encode(id as u32), frame, "{response:?}");
+        }
+    }
+
+    #[test]
+    fn frames_are_read_one_at_a_time() {
+        let mut stream = Request::Ping.encode(1);
+        stream.extend_from_slice(&Request::Flush.encode(2));
+        let mut reader = &stream[..];
+
+        assert_eq!(
+            Request::decode(&read_frame(&mut reader).unwrap().unwrap()).unwrap(),
+            (1, Request::Ping)
+        );
+        assert_eq!(
+            Request::decode(&read_frame(&mut reader).unwrap().unwrap()).unwrap(),
+            (2, Request::Flush)
+        );
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_frames_are_errors() {
+        let frame = Request::Get {
+            key: b"key".to_vec(),
+        }
+        .encode(1);
+        assert!(read_frame(&mut &frame[..frame.len() - 1]).is_err());
+        assert!(read_frame(&mut &((MAX_FRAME + 1) as u32).to_be_bytes()[..]).is_err());
+
+        let mut payload = payload(&frame);
+        payload.truncate(payload.len() - 1);
+        assert!(Request::decode(&payload).is_err());
+        assert!(Request::decode(&[0, 0, 0, 1, 99]).is_err());
+        assert!(Response::decode(&[0, 0, 0, 1, 99]).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    archive::{Retention, WalArchive},
+    wal::{list_wal_ids, wal_path},
+};
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const CHECKPOINT_FILE: &str = "CHECKPOINT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    // Every record written at or before the given time
+    Timestamp(SystemTime),
+    // Every record up to and including the log with the given WAL id
+    WalSeq(usize),
+}
+
+impl RecoveryTarget {
+    pub fn includes_log(&self, wal_id: usize) -> bool {
+        match self {
+            RecoveryTarget::Timestamp(_) => true,
+            RecoveryTarget::WalSeq(seq) => wal_id <= *seq,
+        }
+    }
+
+    pub fn includes_record(&self, timestamp: u64) -> bool {
+        match self {
+            RecoveryTarget::Timestamp(time) => {
+                let target = time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis() as u64)
+                    .unwrap_or_default();
+                timestamp <= target
+            }
+            RecoveryTarget::WalSeq(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    pub logs: usize,
+    pub records: usize,
+    pub skipped: usize,
+    pub dropped_bytes: u64,
+    pub last_timestamp: Option<u64>,
+}
+
+// A checkpoint holds copies of the segments plus the id of the oldest WAL they do not cover
+pub fn read_checkpoint(dir: &Path) -> Result<Option<usize>> {
+    let path = dir.join(CHECKPOINT_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?.trim().parse()?))
+}
+
+pub fn write_checkpoint(dir: &Path, wal_id: usize) -> Result<()> {
+    fs::write(dir.join(CHECKPOINT_FILE), format!("{wal_id}\n"))?;
+    Ok(())
+}
+
+// Archived and live logs of a store with ids from `first_id` on, oldest first
+pub fn logs_since(store_dir: &Path, first_id: usize) -> Result<Vec<(usize, PathBuf)>> {
+    let archive = WalArchive::new(store_dir.join("archive"), Retention::default());
+    let mut logs: Vec<(usize, PathBuf)> = archive
+        .list()?
+        .into_iter()
+        .map(|wal| (wal.seq, wal.path))
+        .chain(
+            list_wal_ids(store_dir)?
+                .into_iter()
+                .map(|id| (id, wal_path(store_dir, id))),
+        )
+        .filter(|(id, _)| *id >= first_id)
+        .collect();
+    logs.sort_by_key(|(id, _)| *id);
+    logs.dedup_by_key(|(id, _)| *id);
+    Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::store::{KvStore, Options};
+    use crate::testutil;
+    use std::{sync::Arc, thread, time::Duration};
+
+    fn open(dir: &Path) -> Arc<KvStore<Vec<u8>>> {
+        KvStore::open(dir, Options::with_secret(Secret::Key([3; 32]))).unwrap()
+    }
+
+    // A point in time with a clear millisecond on either side of it
+    fn pause() -> SystemTime {
+        thread::sleep(Duration::from_millis(10));
+        let now = SystemTime::now();
+        thread::sleep(Duration::from_millis(10));
+        now
+    }
+
+    #[test]
+    fn restores_up_to_a_timestamp_between_two_writes() {
+        let (source_dir, target_dir) = (
+            testutil::temp_dir("recovery"),
+            testutil::temp_dir("recovery"),
+        );
+        let source = open(&source_dir);
+        source.put(b"a", b"1".to_vec()).unwrap();
+        source.put(b"shared", b"old".to_vec()).unwrap();
+        let between = pause();
+        source.put(b"b", b"2".to_vec()).unwrap();
+        source.put(b"shared", b"new".to_vec()).unwrap();
+
+        let target = open(&target_dir);
+        let report = target
+            .restore_to_point(&source_dir, RecoveryTarget::Timestamp(between))
+            .unwrap();
+        assert_eq!(report.records, 2);
+        assert_eq!(target.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(target.get(b"shared").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(target.get(b"b").unwrap(), None);
+
+        source.flush().unwrap();
+        target.flush().unwrap();
+        fs::remove_dir_all(source_dir).unwrap();
+        fs::remove_dir_all(target_dir).unwrap();
+    }
+
+    #[test]
+    fn restores_a_checkpoint_from_archived_and_live_logs() {
+        let (source_dir, target_dir) = (
+            testutil::temp_dir("recovery"),
+            testutil::temp_dir("recovery"),
+        );
+        let source = open(&source_dir);
+        for n in 0..4u8 {
+            source.put(&[n], vec![n]).unwrap();
+        }
+        source.flush().unwrap();
+        source.checkpoint(&target_dir).unwrap();
+
+        // Enough writes after the checkpoint that some of their logs are archived
+        for n in 4..10u8 {
+            source.put(&[n], vec![n]).unwrap();
+        }
+        let between = pause();
+        source.delete(&[0]).unwrap();
+        source.flush().unwrap();
+        assert!(
+            !WalArchive::new(source_dir.join("archive"), Retention::default())
+                .list()
+                .unwrap()
+                .is_empty()
+        );
+
+        let target = open(&target_dir);
+        let report = target
+            .restore_to_point(&source_dir, RecoveryTarget::Timestamp(between))
+            .unwrap();
+        assert_eq!(report.records, 6);
+        for n in 0..10u8 {
+            assert_eq!(target.get(&[n]).unwrap(), Some(vec![n]), "key {n}");
+        }
+
+        target.flush().unwrap();
+        fs::remove_dir_all(source_dir).unwrap();
+        fs::remove_dir_all(target_dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use crate::header::{HEADER_FILE, StoreHeader};
+use crate::store::write_segment;
+use crate::wal::sync_dir;
+use anyhow::{Error, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+// A change to the key slots writes the new header as `STORE.rekey` first, then every file from
+// before data keys again next to the original as `<name>.rekey`, sealed under data keys of its
+// own. Renaming that header into place is the switchover: staged files are thrown away while it
+// is still pending and replace the originals once it is not, so a crash at any point leaves the
+// store with one set of slots or the other. Files with a data key stay as they are, the master
+// key they are wrapped under does not change.
+const REKEY_SUFFIX: &str = "rekey";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RekeyProgress {
+    pub files_done: usize,
+    // Note: grows by the logs written while the change was running
+    pub files_total: usize,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RekeyReport {
+    // The slot the new password went into
+    pub slot: u8,
+    pub segments: usize,
+    pub blob_files: usize,
+    pub logs: usize,
+    pub bytes_written: u64,
+}
+
+// A change to the key slots running in the background
+#[derive(Debug)]
+pub struct PasswordChange {
+    progress: Arc<Mutex<RekeyProgress>>,
+    handle: JoinHandle<Result<RekeyReport>>,
+}
+
+impl PasswordChange {
+    pub fn new(
+        progress: Arc<Mutex<RekeyProgress>>,
+        handle: JoinHandle<Result<RekeyReport>>,
+    ) -> Self {
+        Self { progress, handle }
+    }
+
+    pub fn progress(&self) -> RekeyProgress {
+        *self.progress.lock().expect("rekey progress lock")
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    // Blocks until the new password opens the store, or the change failed and it does not
+    pub fn wait(self) -> Result<RekeyReport> {
+        self.handle
+            .join()
+            .map_err(|_| Error::msg("rekey: background thread panicked"))?
+    }
+}
+
+pub fn begin(dir: &Path, header: &StoreHeader) -> Result<()> {
+    write_segment(
+        &staged_path(&dir.join(HEADER_FILE), REKEY_SUFFIX),
+        &header.encode(),
+    )?;
+    sync_dir(dir)
+}
+
+// Writes the new version of the file at `path` next to it
+pub fn stage(path: &Path, bytes: &[u8], progress: &Mutex<RekeyProgress>) -> Result<()> {
+    write_segment(&staged_path(path, REKEY_SUFFIX), bytes)?;
+    let mut progress = progress.lock().expect("rekey progress lock");
+    progress.files_done += 1;
+    progress.bytes_written += bytes.len() as u64;
+    Ok(())
+}
+
+// Counts a file that stays as it is
+pub fn keep(progress: &Mutex<RekeyProgress>) {
+    progress.lock().expect("rekey progress lock").files_done += 1;
+}
+
+pub fn commit(dir: &Path) -> Result<()> {
+    for staged_dir in staged_dirs(dir) {
+        sync_dir(&staged_dir)?;
+    }
+    fs::rename(
+        staged_path(&dir.join(HEADER_FILE), REKEY_SUFFIX),
+        dir.join(HEADER_FILE),
+    )?;
+    sync_dir(dir)?;
+    recover(dir)
+}
+
+// Finishes or undoes a password change that was interrupted
+// Note: `STORE` is only ever replaced by the rename that commits a change, so the secret can be
+// checked against it first and a wrong one never gets this far
+pub fn recover(dir: &Path) -> Result<()> {
+    let pending_header = staged_path(&dir.join(HEADER_FILE), REKEY_SUFFIX);
+    let committed = !pending_header.exists();
+    for staged_dir in staged_dirs(dir) {
+        for staged in staged_files(&staged_dir)? {
+            if staged == pending_header {
+                continue;
+            }
+            match committed {
+                true => fs::rename(&staged, staged.with_extension(""))?,
+                false => fs::remove_file(&staged)?,
+            }
+        }
+        sync_dir(&staged_dir)?;
+    }
+    // Note: removed last, with the header gone the staged files left would be renamed into place
+    if !committed {
+        fs::remove_file(&pending_header)?;
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+fn staged_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".");
+    staged.push(suffix);
+    PathBuf::from(staged)
+}
+
+fn staged_dirs(dir: &Path) -> Vec<PathBuf> {
+    let archive_dir = dir.join("archive");
+    match archive_dir.exists() {
+        true => vec![dir.to_path_buf(), archive_dir],
+        false => vec![dir.to_path_buf()],
+    }
+}
+
+fn staged_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(|entry_result| Some(entry_result.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == REKEY_SUFFIX))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::store::{KvStore, Options, WrongPassword, list_segment_ids, segment_path};
+    use crate::testutil;
+    use std::thread;
+
+    const OLD: Secret = Secret::Key([1; 32]);
+    const NEW: Secret = Secret::Key([3; 32]);
+
+    fn open(dir: &Path, secret: Secret) -> Result<Arc<KvStore<Vec<u8>>>> {
+        KvStore::open(dir, Options::with_secret(secret))
+    }
+
+    // A store with a segment and a live log, and the header a change to NEW would commit
+    fn store_with_data(dir: &Path) -> StoreHeader {
+        let store = open(dir, OLD).unwrap();
+        for n in 0..10u8 {
+            store.put(&[n], vec![n]).unwrap();
+        }
+        store.flush().unwrap();
+        store.put(b"logged", b"v".to_vec()).unwrap();
+        drop(store);
+        let current = StoreHeader::read(dir).unwrap().unwrap();
+        current.with_password(&OLD, &NEW).unwrap().0
+    }
+
+    fn assert_opens_everything(dir: &Path, secret: Secret) {
+        let store = open(dir, secret).unwrap();
+        for n in 0..10u8 {
+            assert_eq!(store.get(&[n]).unwrap(), Some(vec![n]));
+        }
+        assert_eq!(store.get(b"logged").unwrap(), Some(b"v".to_vec()));
+        assert!(staged_files(dir).unwrap().is_empty());
+    }
+
+    fn assert_wrong_password(dir: &Path, secret: Secret) {
+        let Err(err) = open(dir, secret) else {
+            panic!("opened with a wrong password");
+        };
+        assert!(err.downcast_ref::<WrongPassword>().is_some(), "{err}");
+    }
+
+    #[test]
+    fn crash_before_the_header_moves_keeps_the_old_password() {
+        let dir = testutil::temp_dir("rekey");
+        let header = store_with_data(&dir);
+        begin(&dir, &header).unwrap();
+        let progress = Mutex::default();
+        for id in list_segment_ids(&dir).unwrap() {
+            stage(&segment_path(&dir, id), b"half written", &progress).unwrap();
+        }
+
+        assert_wrong_password(&dir, NEW);
+        assert_opens_everything(&dir, OLD);
+        assert!(!dir.join("STORE.rekey").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crash_after_the_header_moves_keeps_the_new_password() {
+        let dir = testutil::temp_dir("rekey");
+        let header = store_with_data(&dir);
+        begin(&dir, &header).unwrap();
+        let progress = Mutex::default();
+        for id in list_segment_ids(&dir).unwrap() {
+            let path = segment_path(&dir, id);
+            stage(&path, &fs::read(&path).unwrap(), &progress).unwrap();
+        }
+        fs::rename(dir.join("STORE.rekey"), dir.join(HEADER_FILE)).unwrap();
+
+        assert_wrong_password(&dir, OLD);
+        assert_opens_everything(&dir, NEW);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_and_writes_go_on_during_a_change() {
+        let dir = testutil::temp_dir("rekey");
+        store_with_data(&dir);
+        let store = open(&dir, OLD).unwrap();
+
+        let writer_store = store.clone();
+        let writer = thread::spawn(move || {
+            for n in 0..200u8 {
+                writer_store.put(&[1, n], vec![n]).unwrap();
+                assert_eq!(writer_store.get(&[n % 10]).unwrap(), Some(vec![n % 10]));
+            }
+        });
+        let change = store.change_password(&OLD, &NEW).unwrap();
+        for n in 0..200u8 {
+            store.put(&[2, n], vec![n]).unwrap();
+            assert_eq!(store.get(&[n % 10]).unwrap(), Some(vec![n % 10]));
+        }
+        writer.join().unwrap();
+        let report = change.wait().unwrap();
+        assert_eq!(store.key_slots().unwrap().len(), 1);
+        store.flush().unwrap();
+        drop(store);
+
+        assert_wrong_password(&dir, OLD);
+        assert_opens_everything(&dir, NEW);
+        let store = open(&dir, NEW).unwrap();
+        for n in 0..200u8 {
+            assert_eq!(store.get(&[1, n]).unwrap(), Some(vec![n]));
+            assert_eq!(store.get(&[2, n]).unwrap(), Some(vec![n]));
+        }
+        assert_eq!(report.slot, 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
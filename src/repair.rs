@@ -0,0 +1,328 @@
+use crate::compression::Compression;
+use crate::encryption::{Decrypter, DefaultDecrypter, Secret};
+use crate::header;
+use crate::keyring::{FileKey, Keyring};
+use crate::recovery::logs_since;
+use crate::rekey;
+use crate::segment::{RawEntry, SEGMENT_VERSION, SegmentBuilder, SegmentFile};
+use crate::store::{list_segment_ids, recover_compaction, segment_path, write_segment};
+use crate::verify::verify_segment;
+use crate::wal::{read_records, sync_dir};
+use anyhow::{Error, Result};
+use serde::de::DeserializeOwned;
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone)]
+pub struct RepairReport {
+    pub segment: usize,
+    // Entries that authenticated and went into the rebuilt segment
+    pub kept: usize,
+    // Where the original went, `None` when nothing authenticated and it was left in place
+    pub quarantined: Option<PathBuf>,
+}
+
+// Rebuilds every segment that fails verification from the entries that still authenticate,
+// the originals are moved to `quarantine/`. The store must not be open while this runs.
+pub fn repair_dir<V>(dir: &Path, secret: &Secret) -> Result<Vec<RepairReport>>
+where
+    V: DeserializeOwned,
+{
+    let keyring = Arc::new(header::keyring(dir, secret)?);
+    // Note: a password change cut short would otherwise look like damage
+    rekey::recover(dir)?;
+    recover_compaction(dir)?;
+    let mut reports = Vec::new();
+    let mut legacy = None;
+    for id in list_segment_ids(dir)? {
+        let path = segment_path(dir, id);
+        if verify_segment::<V>(&path, &keyring).is_ok() {
+            continue;
+        }
+        // Note: only derived once something needs repairing, every salt costs an Argon2 run
+        let legacy = match &mut legacy {
+            Some(legacy) => legacy,
+            None => legacy.insert(legacy_decrypters(dir, &keyring)?),
+        };
+        reports.push(repair_segment(dir, id, &keyring, legacy)?);
+    }
+    Ok(reports)
+}
+
+fn repair_segment(
+    dir: &Path,
+    id: usize,
+    keyring: &Keyring,
+    legacy: &[(FileKey, DefaultDecrypter)],
+) -> Result<RepairReport> {
+    let path = segment_path(dir, id);
+    let footer = File::open(&path)
+        .map_err(Error::from)
+        .and_then(|mut seg_file| SegmentFile::parse_footer(&mut seg_file))
+        .ok();
+
+    // Note: a data key is only kept in its segment's footer, without the footer only the salts
+    // the store has written with are left to try
+    let mut candidates = Vec::new();
+    if let Some(footer) = &footer
+        && let Ok(decrypter) = keyring.decrypter(&footer.key)
+    {
+        candidates.push((footer.key.clone(), decrypter));
+    }
+    candidates.extend(legacy.iter().cloned());
+
+    // Note: without the footer tombstones are taken to be flagged, as the store writes them now
+    let tombstones = footer.as_ref().is_none_or(|footer| footer.tombstones);
+    let (key, mut entries) = salvage(&fs::read(&path)?, &candidates, tombstones);
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries.dedup_by(|a, b| a.key == b.key);
+
+    // Note: most likely the wrong password, quarantining would hide the segment from the right one
+    let Some(key) = key else {
+        return Ok(RepairReport {
+            segment: id,
+            kept: 0,
+            quarantined: None,
+        });
+    };
+
+    // Note: the codec is only known from the footer, values salvaged from a segment whose footer
+    // is gone are taken to be uncompressed and fail verification if they were not
+    let compression = footer.map_or(Compression::None, |footer| footer.compression);
+
+    let mut builder = SegmentBuilder::new(entries.len());
+    for entry in &entries {
+        builder.add(entry);
+    }
+    let tmp_path = dir.join(format!("segment_{id}.sstable.tmp"));
+    write_segment(&tmp_path, &builder.finish(&key, compression))?;
+
+    let quarantined = quarantine(dir, &path, id)?;
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(dir)?;
+
+    Ok(RepairReport {
+        segment: id,
+        kept: entries.len(),
+        quarantined: Some(quarantined),
+    })
+}
+
+// Tries to read an entry at every offset of the file, so entries after a corrupt one are found
+// no matter what the footer and index say. The first entry that authenticates settles the
+// key, a segment only has one.
+fn salvage(
+    bytes: &[u8],
+    candidates: &[(FileKey, DefaultDecrypter)],
+    tombstones: bool,
+) -> (Option<FileKey>, Vec<RawEntry>) {
+    let mut found_key: Option<usize> = None;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+
+    'scan: while pos < bytes.len() {
+        for version in [SEGMENT_VERSION, 1] {
+            // Note: most offsets hold garbage lengths, they are ruled out before anything is copied
+            let Some(entry_len) = RawEntry::encoded_len(&bytes[pos..], version) else {
+                continue;
+            };
+            let mut cursor = &bytes[pos..pos + entry_len];
+            let Ok(entry) = RawEntry::read_from(&mut cursor, version, tombstones) else {
+                continue;
+            };
+
+            let opens = |decrypter: &DefaultDecrypter| {
+                let mut sealed = entry.sealed.clone();
+                decrypter
+                    .decrypt(&mut sealed, entry.nonce, &mut entry.aad())
+                    .is_ok()
+            };
+            let found = match found_key {
+                Some(i) => opens(&candidates[i].1).then_some(i),
+                None => candidates
+                    .iter()
+                    .position(|(_, decrypter)| opens(decrypter)),
+            };
+            if let Some(i) = found {
+                found_key = Some(i);
+                entries.push(entry);
+                pos += entry_len;
+                continue 'scan;
+            }
+        }
+        pos += 1;
+    }
+    (found_key.map(|i| candidates[i].0.clone()), entries)
+}
+
+// A damaged footer from before data keys may have lost the salt, so every salt the store is
+// known to have written with is tried: segment footers first, then the WAL records
+fn legacy_decrypters(dir: &Path, keyring: &Keyring) -> Result<Vec<(FileKey, DefaultDecrypter)>> {
+    let mut salts: Vec<[u8; 16]> = Vec::new();
+    for id in list_segment_ids(dir)? {
+        let Ok(mut seg_file) = File::open(segment_path(dir, id)) else {
+            continue;
+        };
+        let Ok(footer) = SegmentFile::parse_footer(&mut seg_file) else {
+            continue;
+        };
+        if let FileKey::Salt(salt) = footer.key {
+            salts.push(salt);
+        }
+    }
+    for (_, path) in logs_since(dir, 0)? {
+        let Ok(recovery) = read_records(&fs::read(path)?) else {
+            continue;
+        };
+        if recovery.key.is_none() {
+            salts.extend(recovery.records.iter().map(|record| record.salt));
+        }
+    }
+
+    let mut candidates: Vec<(FileKey, DefaultDecrypter)> = Vec::new();
+    for salt in salts {
+        let key = FileKey::Salt(salt);
+        if candidates.iter().any(|(known, _)| *known == key) {
+            continue;
+        }
+        let decrypter = keyring.decrypter(&key)?;
+        candidates.push((key, decrypter));
+    }
+    Ok(candidates)
+}
+
+fn quarantine(dir: &Path, path: &Path, id: usize) -> Result<PathBuf> {
+    let quarantine_dir = dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine_dir)?;
+
+    let mut dest = quarantine_dir.join(format!("segment_{id}.sstable"));
+    let mut n = 1;
+    while dest.exists() {
+        dest = quarantine_dir.join(format!("segment_{id}.sstable.{n}"));
+        n += 1;
+    }
+    fs::rename(path, &dest)?;
+    sync_dir(&quarantine_dir)?;
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment;
+    use crate::store::{KvStore, Options};
+    use crate::testutil;
+    use crate::verify::verify_dir;
+
+    const SECRET: Secret = Secret::Key([1; 32]);
+
+    fn key(n: u8) -> Vec<u8> {
+        format!("key-{n:02}").into_bytes()
+    }
+
+    // A store whose entries all sit in one segment, returned with the offsets they start at
+    fn one_segment(dir: &Path, count: u8) -> (PathBuf, Vec<u64>) {
+        let store = KvStore::<Vec<u8>>::open(dir, Options::with_secret(SECRET)).unwrap();
+        for n in 0..count {
+            store.put(&key(n), vec![n; 40]).unwrap();
+        }
+        store.flush().unwrap();
+        store.compact().unwrap();
+        let ids = list_segment_ids(dir).unwrap();
+        assert_eq!(ids.len(), 1);
+
+        let path = segment_path(dir, ids[0]);
+        let mut seg_file = File::open(&path).unwrap();
+        let footer = SegmentFile::parse_footer(&mut seg_file).unwrap();
+        let mut starts: Vec<u64> = segment::read_entries(&seg_file, &footer)
+            .unwrap()
+            .entries
+            .iter()
+            .map(|(offset, _)| *offset)
+            .collect();
+        starts.push(footer.idx_offset);
+        (path, starts)
+    }
+
+    fn damage(path: &Path, offsets: &[u64]) -> Vec<u8> {
+        let mut bytes = fs::read(path).unwrap();
+        for offset in offsets {
+            bytes[*offset as usize] ^= 0xff;
+        }
+        fs::write(path, &bytes).unwrap();
+        bytes
+    }
+
+    fn readable(dir: &Path, count: u8) -> Vec<u8> {
+        let store = KvStore::<Vec<u8>>::open(dir, Options::with_secret(SECRET)).unwrap();
+        (0..count)
+            .filter(|n| store.get(&key(*n)).unwrap() == Some(vec![*n; 40]))
+            .collect()
+    }
+
+    #[test]
+    fn entries_around_damaged_ones_are_kept() {
+        let dir = testutil::temp_dir("repair");
+        let (path, starts) = one_segment(&dir, 12);
+        // The last byte of entries 3 and 7, inside their authentication tags
+        let damaged = damage(&path, &[starts[4] - 1, starts[8] - 1]);
+
+        let reports = repair_dir::<Vec<u8>>(&dir, &SECRET).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].kept, 10);
+        let quarantined = reports[0].quarantined.clone().unwrap();
+        assert_eq!(quarantined.parent().unwrap(), dir.join(QUARANTINE_DIR));
+        assert_eq!(fs::read(quarantined).unwrap(), damaged);
+
+        assert!(verify_dir::<Vec<u8>>(&dir, &SECRET).unwrap().is_ok());
+        let expected: Vec<u8> = (0..12).filter(|n| ![3, 7].contains(n)).collect();
+        assert_eq!(readable(&dir, 12), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_damaged_index_and_footer_lose_no_entry() {
+        let dir = testutil::temp_dir("repair");
+        let (path, starts) = one_segment(&dir, 12);
+        let index_start = starts[starts.len() - 1];
+        let footer = SegmentFile::parse_footer(&mut File::open(&path).unwrap()).unwrap();
+        // Key bytes of the index, and the index offset and size the footer records
+        let damaged = damage(
+            &path,
+            &[
+                index_start + 5,
+                index_start + 6,
+                footer.footer_offset + 7,
+                footer.footer_offset + 15,
+            ],
+        );
+        assert!(!verify_dir::<Vec<u8>>(&dir, &SECRET).unwrap().is_ok());
+
+        let reports = repair_dir::<Vec<u8>>(&dir, &SECRET).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].kept, 12);
+        let quarantined = reports[0].quarantined.clone().unwrap();
+        assert_eq!(quarantined.parent().unwrap(), dir.join(QUARANTINE_DIR));
+        assert_eq!(fs::read(quarantined).unwrap(), damaged);
+
+        assert!(verify_dir::<Vec<u8>>(&dir, &SECRET).unwrap().is_ok());
+        assert_eq!(readable(&dir, 12), (0..12).collect::<Vec<_>>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sound_segments_are_left_alone() {
+        let dir = testutil::temp_dir("repair");
+        let (path, _) = one_segment(&dir, 6);
+        let before = fs::read(&path).unwrap();
+        assert!(repair_dir::<Vec<u8>>(&dir, &SECRET).unwrap().is_empty());
+        assert_eq!(fs::read(&path).unwrap(), before);
+        assert!(!dir.join(QUARANTINE_DIR).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
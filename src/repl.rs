@@ -0,0 +1,390 @@
+use crate::store::KvStore;
+use anyhow::{Error, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use std::{
+    io::{BufRead, Write, stdin, stdout},
+    time::Duration,
+};
+
+const HELP: &str = "\
+SET <key> <value> [EX <secs>]   store a value, optionally expiring
+GET <key>                       print a value
+DEL <key>                       delete a key
+EXPIRE <key> <secs>             set a deadline on an existing key
+SCAN [<after>] [<count>]        list keys in order, after the given key, 10 at a time
+FLUSH                           write the memtable to a segment
+STATS                           store and WAL counters
+HELP                            this text
+QUIT                            leave
+
+Arguments are separated by whitespace. Quote them to keep spaces:
+  \"double quoted\" understands \\n \\r \\t \\0 \\\\ \\\" and \\xHH escapes
+  'single quoted' is taken as is
+Binary literals: 0x68656c6c6f (hex) and b64:aGVsbG8= (base64).
+Keys and values are shown so they can be typed back: quoted where plain text would read
+differently, as hex literals where they are not text.";
+
+pub fn run(store: &KvStore<Vec<u8>>) -> Result<()> {
+    run_with(store, stdin().lock(), stdout().lock())
+}
+
+// One reply per input line, errors are replied with `ERR` instead of ending the loop
+pub fn run_with(
+    store: &KvStore<Vec<u8>>,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let args = match tokenize(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(err) => {
+                writeln!(output, "ERR {err}")?;
+                continue;
+            }
+        };
+
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        if matches!(name.as_str(), "QUIT" | "EXIT") {
+            break;
+        }
+        match execute(store, &name, &args[1..]) {
+            Ok(reply) => writeln!(output, "{reply}")?,
+            Err(err) => writeln!(output, "ERR {err}")?,
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn execute(store: &KvStore<Vec<u8>>, name: &str, args: &[Vec<u8>]) -> Result<String> {
+    let reply = match (name, args) {
+        ("HELP", _) => HELP.to_string(),
+        ("SET", [key, value]) => {
+            store.put(key, value.clone())?;
+            "SET done".to_string()
+        }
+        ("SET", [key, value, ex, secs]) if ex.eq_ignore_ascii_case(b"EX") => {
+            store.put_with_ttl(key, value.clone(), seconds(secs)?)?;
+            "SET done".to_string()
+        }
+        ("GET", [key]) => match store.get(key)? {
+            Some(value) => format!("GET -> {}", display_bytes(&value)),
+            None => "Not found".to_string(),
+        },
+        ("DEL", [key]) => {
+            store.delete(key)?;
+            "DEL done".to_string()
+        }
+        ("EXPIRE", [key, secs]) => match store.expire(key, seconds(secs)?)? {
+            true => "EXPIRE done".to_string(),
+            false => "Not found".to_string(),
+        },
+        ("SCAN", rest) if rest.len() <= 2 => {
+            let after = rest.first().map(Vec::as_slice);
+            let count = match rest.get(1) {
+                Some(count) => match String::from_utf8_lossy(count).parse() {
+                    Ok(count) if count > 0 => count,
+                    _ => return Err(anyhow!("count must be a positive number")),
+                },
+                None => 10,
+            };
+            let mut page = store.scan(after, count)?;
+            if page.entries.len() > count {
+                page.entries.truncate(count);
+                page.next = page.entries.last().map(|(key, _)| key.clone());
+            }
+            let mut lines: Vec<String> = page
+                .entries
+                .iter()
+                .map(|(key, value)| format!("{} -> {}", display_bytes(key), display_bytes(value)))
+                .collect();
+            if let Some(next) = page.next {
+                lines.push(format!("(more after {})", display_bytes(&next)));
+            }
+            if lines.is_empty() {
+                "(empty)".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        ("FLUSH", []) => {
+            store.flush()?;
+            "FLUSH done".to_string()
+        }
+        ("STATS", []) => {
+            let stats = store.stats();
+            format!(
+                "segments {}, memtable entries {}, frozen memtables {}, wal {} ({} bytes appended, {} syncs)",
+                stats.segments,
+                stats.memtable_entries,
+                stats.frozen_memtables,
+                stats.wal_id,
+                stats.wal.appended_bytes,
+                stats.wal.syncs
+            )
+        }
+        ("SET" | "GET" | "DEL" | "EXPIRE" | "SCAN" | "FLUSH" | "STATS", _) => {
+            return Err(anyhow!("wrong number of arguments for {name}, try HELP"));
+        }
+        _ => return Err(anyhow!("unknown command {name}, try HELP")),
+    };
+    Ok(reply)
+}
+
+fn seconds(arg: &[u8]) -> Result<Duration> {
+    let secs: u64 = String::from_utf8_lossy(arg)
+        .parse()
+        .map_err(|_| anyhow!("seconds must be a positive number"))?;
+    if secs == 0 {
+        return Err(anyhow!("seconds must be a positive number"));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+// Text as is, quoted with escapes where the tokenizer would read it differently, and anything
+// else as a hex literal, so every form reads back as the same bytes
+pub fn display_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !needs_quotes(text) => text.to_string(),
+        Ok(text) => {
+            let mut quoted = String::from("\"");
+            for c in text.chars() {
+                match c {
+                    '\n' => quoted.push_str("\\n"),
+                    '\r' => quoted.push_str("\\r"),
+                    '\t' => quoted.push_str("\\t"),
+                    '\\' => quoted.push_str("\\\\"),
+                    '"' => quoted.push_str("\\\""),
+                    c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+                    c => quoted.push(c),
+                }
+            }
+            quoted.push('"');
+            quoted
+        }
+        Err(_) => {
+            let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            format!("0x{hex}")
+        }
+    }
+}
+
+fn needs_quotes(text: &str) -> bool {
+    text.is_empty()
+        || text.starts_with("0x")
+        || text.starts_with("b64:")
+        || text
+            .chars()
+            .any(|c| c.is_control() || c.is_whitespace() || matches!(c, '"' | '\''))
+}
+
+// Splits a line into arguments, see HELP for the syntax
+pub fn tokenize(line: &str) -> Result<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+
+        let arg = match first {
+            '"' => {
+                chars.next();
+                let mut arg = Vec::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(Error::msg("unterminated double quote")),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push(b'\n'),
+                            Some('r') => arg.push(b'\r'),
+                            Some('t') => arg.push(b'\t'),
+                            Some('0') => arg.push(0),
+                            Some('\\') => arg.push(b'\\'),
+                            Some('"') => arg.push(b'"'),
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16)
+                                    .map_err(|_| anyhow!("invalid escape \\x{hex}"))?;
+                                arg.push(byte);
+                            }
+                            Some(other) => return Err(anyhow!("unknown escape \\{other}")),
+                            None => return Err(Error::msg("unterminated double quote")),
+                        },
+                        Some(c) => arg.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                arg
+            }
+            '\'' => {
+                chars.next();
+                let mut arg = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(Error::msg("unterminated single quote")),
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                    }
+                }
+                arg.into_bytes()
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+                literal(&word)?
+            }
+        };
+
+        // Note: a closing quote has to end the argument, `"a"b` is most likely a typo
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) && matches!(first, '"' | '\'') {
+            return Err(Error::msg("closing quote must be followed by a space"));
+        }
+        args.push(arg);
+    }
+}
+
+fn literal(word: &str) -> Result<Vec<u8>> {
+    if let Some(hex) = word.strip_prefix("0x") {
+        return decode_hex(hex).map_err(|err| anyhow!("{err} in literal {word}"));
+    }
+    if let Some(encoded) = word.strip_prefix("b64:") {
+        return BASE64
+            .decode(encoded)
+            .map_err(|_| anyhow!("invalid base64 literal {word}"));
+    }
+    Ok(word.as_bytes().to_vec())
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::msg("odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| Error::msg("invalid hex digit"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::store::Options;
+    use crate::testutil;
+
+    fn args(line: &str) -> Vec<Vec<u8>> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn words_and_quotes() {
+        assert_eq!(
+            args("  SET  k   v "),
+            vec![b"SET".to_vec(), b"k".to_vec(), b"v".to_vec()]
+        );
+        assert_eq!(
+            args(r#""a b" 'c d' """#),
+            vec![b"a b".to_vec(), b"c d".to_vec(), Vec::new()]
+        );
+        assert_eq!(args(r#""say \"hi\"""#)[0], br#"say "hi""#.to_vec());
+        assert_eq!(args(r"'no \n escapes'"), vec![br"no \n escapes".to_vec()]);
+        assert_eq!(args(r#"a"b"#), vec![br#"a"b"#.to_vec()]);
+        assert!(args("   ").is_empty());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(args(r#""\n\r\t\0\\""#), vec![b"\n\r\t\0\\".to_vec()]);
+        assert_eq!(args(r#""\x00\xff\x7F""#), vec![vec![0, 0xff, 0x7f]]);
+        assert!(tokenize(r#""\xg0""#).is_err());
+        assert!(tokenize(r#""\x4""#).is_err());
+        assert!(tokenize(r#""\q""#).is_err());
+    }
+
+    #[test]
+    fn binary_literals() {
+        assert_eq!(
+            args("0x68656c6c6f b64:aGVsbG8="),
+            vec![b"hello".to_vec(); 2]
+        );
+        assert_eq!(args("0x"), vec![Vec::new()]);
+        assert_eq!(args("0xFF00"), vec![vec![0xff, 0]]);
+        assert!(tokenize("0x123").is_err());
+        assert!(tokenize("0xzz").is_err());
+        assert!(tokenize("b64:***").is_err());
+        // Quoted, they are text
+        assert_eq!(
+            args(r#""0x00" 'b64:AA=='"#),
+            vec![b"0x00".to_vec(), b"b64:AA==".to_vec()]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        for line in [r#"SET "k v"#, "SET 'k v", r#"SET "k\"#, r#""\""#] {
+            assert!(tokenize(line).is_err(), "{line}");
+        }
+        assert!(tokenize(r#""a"b"#).is_err());
+    }
+
+    #[test]
+    fn displayed_bytes_read_back_the_same() {
+        let cases: [&[u8]; 14] = [
+            b"plain",
+            b"",
+            b"two words",
+            b"tab\there",
+            b"0x00",
+            b"b64:AA==",
+            b"\"quoted\"",
+            b"it's",
+            b"back\\slash",
+            b"line\nbreak",
+            "caf\u{e9} \u{a0}nbsp".as_bytes(),
+            &[0, 1, 2],
+            &[0xff, 0xfe],
+            b"0xnot hex",
+        ];
+        for bytes in cases {
+            let shown = display_bytes(bytes);
+            assert_eq!(args(&shown), vec![bytes.to_vec()], "{shown}");
+        }
+        assert_eq!(display_bytes(b"plain"), "plain");
+        assert_eq!(display_bytes(b"0x00"), r#""0x00""#);
+    }
+
+    #[test]
+    fn scan_pages_through_the_store() {
+        let dir = testutil::temp_dir("repl");
+        let store = KvStore::open(&dir, Options::with_secret(Secret::Key([1; 32]))).unwrap();
+        let input = "SET a 1\nSET b 2\nSET \"c d\" 3\nSCAN \"\" 2\nSCAN b\nSCAN a 0\nSCAN a x\n";
+        let mut output = Vec::new();
+        run_with(&store, input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[3..],
+            [
+                "a -> 1",
+                "b -> 2",
+                "(more after b)",
+                r#""c d" -> 3"#,
+                "ERR count must be a positive number",
+                "ERR count must be a positive number",
+            ]
+        );
+        store.flush().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{Result, anyhow};
+use std::io::{BufRead, Read, Write};
+
+// Note: mirrors the default proto-max-bulk-len of Redis
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Reply::Error(message.into())
+    }
+
+    pub fn bulk(bytes: impl Into<Vec<u8>>) -> Self {
+        Reply::Bulk(Some(bytes.into()))
+    }
+
+    pub fn nil() -> Self {
+        Reply::Bulk(None)
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Reply::Simple(line) => write!(out, "+{}\r\n", single_line(line)),
+            Reply::Error(line) => write!(out, "-{}\r\n", single_line(line)),
+            Reply::Integer(n) => write!(out, ":{n}\r\n"),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn single_line(line: &str) -> String {
+    line.replace(['\r', '\n'], " ")
+}
+
+// Next command as its arguments, `None` on a clean end of stream
+// Both multibulk arrays and plain inline commands (as sent by telnet or nc) are accepted
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader, MAX_INLINE_LEN)? else {
+            return Ok(None);
+        };
+        let Some(count) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        };
+
+        let count = parse_len(count, MAX_ARRAY_LEN)?;
+        let mut args = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            let header = read_line(reader, MAX_INLINE_LEN)?
+                .ok_or_else(|| anyhow!("unexpected end of stream"))?;
+            let len = header
+                .strip_prefix(b"$")
+                .ok_or_else(|| anyhow!("Protocol error: expected '$'"))?;
+            let len = parse_len(len, MAX_BULK_LEN)?;
+
+            // Note: grows with the data that arrives, a declared length alone allocates nothing
+            let mut arg = Vec::new();
+            Read::take(&mut *reader, len as u64 + 2).read_to_end(&mut arg)?;
+            if arg.len() < len + 2 {
+                return Err(anyhow!("unexpected end of stream"));
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(anyhow!("Protocol error: bulk string not terminated"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+fn read_line(reader: &mut impl BufRead, max: usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = Read::take(&mut *reader, max as u64 + 1).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("Protocol error: line too long or truncated"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    let len: i64 = std::str::from_utf8(digits)?
+        .parse()
+        .map_err(|_| anyhow!("Protocol error: invalid length"))?;
+    if len < 0 || len as usize > max {
+        return Err(anyhow!("Protocol error: invalid length"));
+    }
+    Ok(len as usize)
+}
@@ -1,49 +1,340 @@
-use crate::FOOTER_SIZE;
+use crate::blob::{BlobRef, BlobStore};
+use crate::compression::Compression;
 use crate::encryption::Decrypter;
 use crate::encryption::DefaultDecrypter;
-use anyhow::Result;
-use argon2::password_hash::SaltString;
+use crate::keyring::{FileKey, Keyring, WRAPPED_KEY_LEN};
+use crate::padding::unpad;
+use crate::store::StoredValue;
+use crate::{FOOTER_SIZE, INDEX_DENSITY};
+use anyhow::{Error, Result};
+use serde::de::DeserializeOwned;
 use std::io::Read;
 use std::io::Seek;
-use std::os::windows::fs::FileExt;
-use std::{collections::BTreeMap, fs::File, path::PathBuf};
+use std::{collections::BTreeMap, fs::File, path::PathBuf, sync::Arc};
+
+// Version 2 segments append `version: u32 | magic` after the 32 byte footer and give every
+// entry a flags byte, version 1 segments have neither. Version 3 puts the id of the codec the
+// values were compressed with between the footer and the trailer, version 4 a flags byte after it
+// and version 5 the segment's wrapped data key after that.
+const SEGMENT_MAGIC: &[u8; 4] = b"EKVS";
+pub const SEGMENT_VERSION: u32 = 5;
+const TRAILER_SIZE: usize = 8;
+
+const FLAG_EXPIRES: u8 = 1;
+// The sealed plaintext is a `BlobRef` to the value instead of the value
+const FLAG_BLOB: u8 = 2;
+// The key field holds the key's tag and the key is sealed in front of the value, see `wrap_key`
+pub const FLAG_SEALED_KEY: u8 = 4;
+// The sealed plaintext carries padding, see `padding::unpad`
+pub const FLAG_PADDED: u8 = 8;
+// The entry is a tombstone, only in segments with `FOOTER_TOMBSTONES`
+const FLAG_TOMBSTONE: u8 = 16;
+
+// Footer flag: entries have sealed keys and are ordered and indexed by tag
+const FOOTER_SEALED_KEYS: u8 = 1;
+// Footer flag: entries are sealed under the wrapped data key, not a key derived with the salt
+const FOOTER_WRAPPED_KEY: u8 = 2;
+// Footer flag: tombstones carry `FLAG_TOMBSTONE`. Without it an empty plaintext is a tombstone,
+// which values that encode to nothing (`()`) were taken for.
+const FOOTER_TOMBSTONES: u8 = 4;
+
+// AES-GCM tag, what a sealed empty plaintext (an unflagged tombstone) consists of
+const TAG_LEN: usize = 16;
+
+// Entries in key order plus whether the source has more after the last one
+pub type ScanBatch<V> = (Vec<(Vec<u8>, StoredValue<V>)>, bool);
+
+// Index entries in file order: key and the offset of its entry in the data region
+pub type IndexEntries = Vec<(Vec<u8>, u64)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    pub key: Vec<u8>,
+    pub expires_at: Option<u64>,
+    pub blob: bool,
+    pub sealed_key: bool,
+    pub padded: bool,
+    // `None` in segments without `FOOTER_TOMBSTONES`
+    pub tombstone: Option<bool>,
+    pub nonce: [u8; 12],
+    pub sealed: Vec<u8>,
+}
+
+impl RawEntry {
+    // Note: the blob flag is authenticated too, a pointer is never taken for a value or vice versa
+    pub fn aad(&self) -> Vec<u8> {
+        let mut aad = entry_aad(&self.key, self.expires_at);
+        if self.blob {
+            aad.push(FLAG_BLOB);
+        }
+        if self.sealed_key {
+            aad.push(FLAG_SEALED_KEY);
+        }
+        if self.padded {
+            aad.push(FLAG_PADDED);
+        }
+        if self.tombstone == Some(true) {
+            aad.push(FLAG_TOMBSTONE);
+        }
+        aad
+    }
+
+    // Note: unknown for unflagged sealed keys and padded entries, whose tombstones are not empty
+    pub fn is_tombstone(&self) -> bool {
+        let empty = !self.blob && !self.sealed_key && !self.padded && self.sealed.len() == TAG_LEN;
+        self.tombstone.unwrap_or(empty)
+    }
+
+    // `tombstones` is whether the segment flags its tombstones, see `FOOTER_TOMBSTONES`
+    pub fn read_from(reader: &mut impl Read, version: u32, tombstones: bool) -> Result<Self> {
+        let mut key_len_bytes: [u8; 4] = [0; 4];
+        reader.read_exact(&mut key_len_bytes)?;
+
+        let key = read_vec(reader, u32::from_be_bytes(key_len_bytes))?;
+
+        let mut expires_at = None;
+        let mut blob = false;
+        let mut sealed_key = false;
+        let mut padded = false;
+        let mut tombstone = None;
+        if version >= 2 {
+            let mut flags: [u8; 1] = [0; 1];
+            reader.read_exact(&mut flags)?;
+            let mut known = FLAG_EXPIRES | FLAG_BLOB | FLAG_SEALED_KEY | FLAG_PADDED;
+            if tombstones {
+                known |= FLAG_TOMBSTONE;
+            }
+            if flags[0] & !known != 0 {
+                return Err(Error::msg("segment: unknown entry flags"));
+            }
+            blob = flags[0] & FLAG_BLOB != 0;
+            sealed_key = flags[0] & FLAG_SEALED_KEY != 0;
+            padded = flags[0] & FLAG_PADDED != 0;
+            tombstone = tombstones.then_some(flags[0] & FLAG_TOMBSTONE != 0);
+            if flags[0] & FLAG_EXPIRES != 0 {
+                let mut expires_bytes: [u8; 8] = [0; 8];
+                reader.read_exact(&mut expires_bytes)?;
+                expires_at = Some(u64::from_be_bytes(expires_bytes));
+            }
+        }
+
+        let mut nonce: [u8; 12] = [0; 12];
+        reader.read_exact(&mut nonce)?;
+
+        let mut enc_bytes_len: [u8; 4] = [0; 4];
+        reader.read_exact(&mut enc_bytes_len)?;
+
+        let sealed = read_vec(reader, u32::from_be_bytes(enc_bytes_len))?;
+        if sealed.len() < TAG_LEN {
+            return Err(Error::msg("segment: sealed value shorter than a tag"));
+        }
+
+        Ok(Self {
+            key,
+            expires_at,
+            blob,
+            sealed_key,
+            padded,
+            tombstone,
+            nonce,
+            sealed,
+        })
+    }
+
+    // Length of the entry `bytes` starts with going by its length fields, `None` when they run past
+    // the end. Lets a scan over damaged data rule out an offset without copying anything.
+    pub fn encoded_len(bytes: &[u8], version: u32) -> Option<usize> {
+        let len_at = |pos: usize| {
+            let len: [u8; 4] = bytes.get(pos..pos.checked_add(4)?)?.try_into().ok()?;
+            Some(u32::from_be_bytes(len) as usize)
+        };
+        let mut pos = 4usize.checked_add(len_at(0)?)?;
+        if version >= 2 {
+            if bytes.get(pos)? & FLAG_EXPIRES != 0 {
+                pos += 8;
+            }
+            pos += 1;
+        }
+        pos += 12;
+        let end = (pos + 4).checked_add(len_at(pos)?)?;
+        (end <= bytes.len()).then_some(end)
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.key);
+        let mut flags = 0;
+        if self.expires_at.is_some() {
+            flags |= FLAG_EXPIRES;
+        }
+        if self.blob {
+            flags |= FLAG_BLOB;
+        }
+        if self.sealed_key {
+            flags |= FLAG_SEALED_KEY;
+        }
+        if self.padded {
+            flags |= FLAG_PADDED;
+        }
+        if self.tombstone == Some(true) {
+            flags |= FLAG_TOMBSTONE;
+        }
+        buf.push(flags);
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_be_bytes());
+        }
+        buf.extend_from_slice(&self.nonce);
+        buf.extend_from_slice(&(self.sealed.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.sealed);
+    }
+}
+
+// Note: reads through `take` so a corrupt length fails on the short read instead of allocating it
+fn read_vec(reader: &mut impl Read, len: u32) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(Error::msg("segment: entry runs past the end of the data"));
+    }
+    Ok(buf)
+}
+
+// Plaintext of an entry or log record with a sealed key: `key_len: u32 | key | value`
+pub fn wrap_key(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(4 + key.len() + value.len());
+    plaintext.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    plaintext.extend_from_slice(key);
+    plaintext.extend_from_slice(value);
+    plaintext
+}
+
+pub fn unwrap_key(plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let key_len = plaintext
+        .get(0..4)
+        .map(|len| u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize)
+        .ok_or_else(|| Error::msg("segment: sealed key missing"))?;
+    let key = plaintext
+        .get(4..4 + key_len)
+        .ok_or_else(|| Error::msg("segment: sealed key truncated"))?;
+    Ok((key.to_vec(), plaintext[4 + key_len..].to_vec()))
+}
+
+// The expiry is bound to the ciphertext, entries without one keep the bare key as AAD
+pub fn entry_aad(key: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut aad = key.to_vec();
+    if let Some(expires_at) = expires_at {
+        aad.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    aad
+}
+
+#[derive(Debug)]
+pub struct SegmentBuilder {
+    buf: Vec<u8>,
+    idx: Vec<u8>,
+    added: usize,
+    index_every: usize,
+    sealed_keys: bool,
+    // Entries salvaged from a segment without `FOOTER_TOMBSTONES` keep to its rule
+    unflagged: bool,
+}
+
+impl SegmentBuilder {
+    pub fn new(entry_count: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            idx: Vec::new(),
+            added: 0,
+            index_every: (entry_count / INDEX_DENSITY).max(1),
+            sealed_keys: false,
+            unflagged: false,
+        }
+    }
+
+    // Entries have to be added in order of their key field, the tag for sealed keys
+    pub fn add(&mut self, entry: &RawEntry) {
+        let offset = self.buf.len() as u64;
+        entry.write_to(&mut self.buf);
+        self.sealed_keys |= entry.sealed_key;
+        self.unflagged |= entry.tombstone.is_none();
+
+        if self.added.is_multiple_of(self.index_every) {
+            self.idx
+                .extend_from_slice(&(entry.key.len() as u32).to_be_bytes());
+            self.idx.extend_from_slice(&entry.key);
+            self.idx.extend_from_slice(&offset.to_be_bytes());
+        }
+        self.added += 1;
+    }
+
+    // Note: a salt goes where it always went and leaves the wrapped key zeroed, and the other way round
+    pub fn finish(mut self, key: &FileKey, compression: Compression) -> Vec<u8> {
+        let mut footer: Vec<u8> = vec![0; FOOTER_SIZE];
+        footer[0..8].copy_from_slice(&(self.buf.len() as u64).to_be_bytes());
+        footer[8..16].copy_from_slice(&(self.idx.len() as u64).to_be_bytes());
+        let mut flags = match self.sealed_keys {
+            true => FOOTER_SEALED_KEYS,
+            false => 0,
+        };
+        if !self.unflagged {
+            flags |= FOOTER_TOMBSTONES;
+        }
+        let mut wrapped_key = [0u8; WRAPPED_KEY_LEN];
+        match key {
+            FileKey::Salt(salt) => footer[16..].copy_from_slice(salt),
+            FileKey::Wrapped(wrapped) => {
+                wrapped_key = *wrapped;
+                flags |= FOOTER_WRAPPED_KEY;
+            }
+        }
+
+        self.buf.extend(&self.idx);
+        self.buf.extend_from_slice(&footer);
+        self.buf.push(compression.id());
+        self.buf.push(flags);
+        self.buf.extend_from_slice(&wrapped_key);
+        self.buf.extend_from_slice(&SEGMENT_VERSION.to_be_bytes());
+        self.buf.extend_from_slice(SEGMENT_MAGIC);
+        self.buf
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct SegmentIter {
-    password: String,
+    keyring: Arc<Keyring>,
     curr: usize,
     seg_ids: Vec<usize>,
     origin: PathBuf,
+    blobs: Arc<BlobStore>,
 }
 
 impl SegmentIter {
-    pub fn new(seg_ids: Vec<usize>, origin_path: PathBuf, password: String) -> Self {
+    pub fn new(
+        seg_ids: Vec<usize>,
+        origin_path: PathBuf,
+        keyring: Arc<Keyring>,
+        blobs: Arc<BlobStore>,
+    ) -> Self {
         Self {
             curr: seg_ids.len(),
-            password: password,
-            seg_ids: seg_ids,
+            keyring,
+            seg_ids,
             origin: origin_path,
+            blobs,
         }
     }
 
-    pub fn find_key_in_segments(self, key: &str) -> Result<Option<String>> {
+    // Newest segment first, the first entry found for the key wins even if it is a tombstone
+    pub fn find_key_in_segments<V>(self, key: &[u8]) -> Result<Option<StoredValue<V>>>
+    where
+        V: DeserializeOwned,
+    {
         for seg in self {
-            let mut key_offset: u64 = 0;
             let mut seg = seg?;
-            for (k, v) in seg.idx.iter() {
-                match k.as_str().cmp(key) {
-                    std::cmp::Ordering::Greater => break,
-                    std::cmp::Ordering::Equal => {
-                        key_offset = *v;
-                        break;
-                    }
-                    std::cmp::Ordering::Less => {
-                        key_offset = *v;
-                    }
-                }
-            }
-            if let Ok(Some(v)) = seg.search(key, key_offset) {
-                return Ok(Some(v));
+            let lookup = seg.lookup_key(key);
+            let key_offset = seg.block_offset(&lookup);
+            if let Some(entry) = seg.search(&lookup, key_offset)? {
+                return Ok(Some(entry));
             }
         }
         Ok(None)
@@ -51,15 +342,21 @@ impl SegmentIter {
 
     fn load_segment(&self, seg_path: &PathBuf) -> Result<SegmentFile> {
         let mut seg_file = File::open(seg_path)?;
-        let (offset, size, salt) = SegmentFile::parse_footer(&mut seg_file)?;
-        let mut idx: Vec<u8> = vec![0; size.try_into()?];
+        let footer = SegmentFile::parse_footer(&mut seg_file)?;
+        let idx = read_index(&seg_file, &footer)?;
 
-        let n = seg_file.seek_read(&mut idx, offset)?;
-        assert_eq!(size, n.try_into()?);
-
-        let file_decrypter = DefaultDecrypter::new(self.password.clone(), salt)?;
-        let mut seg = SegmentFile::new(offset, seg_file, file_decrypter);
-        seg.populate_index(idx)?;
+        let file_decrypter = self.keyring.decrypter(&footer.key)?;
+        let mut seg = SegmentFile::new(
+            footer.idx_offset,
+            seg_file,
+            file_decrypter,
+            self.blobs.clone(),
+        );
+        seg.version = footer.version;
+        seg.compression = footer.compression;
+        seg.sealed_keys = footer.sealed_keys;
+        seg.tombstones = footer.tombstones;
+        seg.idx.extend(idx);
 
         Ok(seg)
     }
@@ -68,7 +365,6 @@ impl SegmentIter {
 impl Iterator for SegmentIter {
     type Item = Result<SegmentFile>;
 
-    // Note: assumes a continuous sequence of segments without "gaps"
     fn next(&mut self) -> Option<Self::Item> {
         let curr = self.curr.checked_sub(1)?;
         self.curr = curr;
@@ -81,98 +377,397 @@ impl Iterator for SegmentIter {
     }
 }
 
+#[derive(Debug)]
+pub struct Footer {
+    pub idx_offset: u64,
+    pub idx_size: u64,
+    pub key: FileKey,
+    // Where the wrapped data key or the salt sits in the file
+    pub key_offset: u64,
+    pub version: u32,
+    pub compression: Compression,
+    pub sealed_keys: bool,
+    pub tombstones: bool,
+    // Where the footer starts, which is where the index has to end
+    pub footer_offset: u64,
+}
+
 #[derive(Debug)]
 pub struct SegmentFile {
     seg_handle: File,
     idx_offset: u64,
-    idx: BTreeMap<String, u64>,
+    idx: BTreeMap<Vec<u8>, u64>,
     decrypter: DefaultDecrypter,
+    blobs: Arc<BlobStore>,
+    version: u32,
+    compression: Compression,
+    sealed_keys: bool,
+    tombstones: bool,
 }
 
 impl SegmentFile {
-    pub fn new(offset: u64, seg_file: File, decrypter: DefaultDecrypter) -> Self {
+    pub fn new(
+        offset: u64,
+        seg_file: File,
+        decrypter: DefaultDecrypter,
+        blobs: Arc<BlobStore>,
+    ) -> Self {
         Self {
             idx: BTreeMap::new(),
             idx_offset: offset,
-            decrypter: decrypter,
+            decrypter,
+            blobs,
             seg_handle: seg_file,
+            version: 1,
+            compression: Compression::None,
+            sealed_keys: false,
+            tombstones: false,
+        }
+    }
+
+    // What entries are ordered and indexed by, the key or its tag
+    fn lookup_key(&self, key: &[u8]) -> Vec<u8> {
+        match self.sealed_keys {
+            true => self.decrypter.key_tag(key),
+            false => key.to_vec(),
         }
     }
 
-    fn search<V>(&mut self, k: &str, key_offset: u64) -> Result<Option<V>>
+    // Offset of the last indexed key at or before `key`
+    fn block_offset(&self, key: &[u8]) -> u64 {
+        let mut key_offset: u64 = 0;
+        for (k, v) in self.idx.iter() {
+            match k.as_slice().cmp(key) {
+                std::cmp::Ordering::Greater => break,
+                std::cmp::Ordering::Equal => {
+                    key_offset = *v;
+                    break;
+                }
+                std::cmp::Ordering::Less => {
+                    key_offset = *v;
+                }
+            }
+        }
+        key_offset
+    }
+
+    fn search<V>(&mut self, k: &[u8], key_offset: u64) -> Result<Option<StoredValue<V>>>
     where
-        V: bincode::Decode<()>,
+        V: DeserializeOwned,
     {
         self.seg_handle.seek(std::io::SeekFrom::Start(key_offset))?;
         loop {
             if self.seg_handle.stream_position()? >= self.idx_offset {
                 return Ok(None);
             }
-            let mut key_len_bytes: [u8; 4] = [0; 4];
-            self.seg_handle.read_exact(&mut key_len_bytes)?;
-
-            let key_len = u32::from_be_bytes(key_len_bytes);
-            let mut key_bytes = vec![0; key_len as usize];
-            self.seg_handle.read_exact(&mut key_bytes)?;
-
-            let mut nonce_bytes: [u8; 12] = [0; 12];
-            self.seg_handle.read_exact(&mut nonce_bytes)?;
-
-            let mut enc_bytes_len: [u8; 4] = [0; 4];
-            self.seg_handle.read_exact(&mut enc_bytes_len)?;
-
-            let enc_len = u32::from_be_bytes(enc_bytes_len);
-            let mut enc_bytes = vec![0; enc_len as usize];
-            self.seg_handle.read_exact(&mut enc_bytes)?;
-
-            let key = String::from_utf8(key_bytes.clone())?;
-            if key.as_str() == k {
-                let plaintext_slice =
-                    self.decrypter
-                        .decrypt(&mut enc_bytes, nonce_bytes, &mut key_bytes)?;
-                let value: (V, usize) =
-                    bincode::decode_from_slice(&plaintext_slice, bincode::config::standard())?;
-                return Ok(Some(value.0));
+            let entry = RawEntry::read_from(&mut self.seg_handle, self.version, self.tombstones)?;
+            if entry.key.as_slice() == k {
+                return Ok(Some(self.open_entry(entry)?));
             }
-            if key.as_str() > k {
+            if entry.key.as_slice() > k {
                 return Ok(None);
             }
         }
     }
 
-    fn populate_index(&mut self, idx_bytes: Vec<u8>) -> Result<()> {
-        let mut idx_cursor = &idx_bytes[..];
-        while !idx_cursor.is_empty() {
-            let mut key_len_bytes: [u8; 4] = [0; 4];
-            idx_cursor.read_exact(&mut key_len_bytes)?;
+    // Up to `limit` entries with keys after `after`, and whether the segment has more
+    pub fn scan<V>(&mut self, after: Option<&[u8]>, limit: usize) -> Result<ScanBatch<V>>
+    where
+        V: DeserializeOwned,
+    {
+        if self.sealed_keys {
+            return self.scan_sealed(after, limit);
+        }
+        let start = after.map_or(0, |key| self.block_offset(key));
+        self.seg_handle.seek(std::io::SeekFrom::Start(start))?;
 
-            let key_len = u32::from_be_bytes(key_len_bytes);
-            let mut key_bytes = vec![0; key_len as usize];
-            idx_cursor.read_exact(&mut key_bytes)?;
+        let mut entries = Vec::new();
+        while self.seg_handle.stream_position()? < self.idx_offset {
+            let entry = RawEntry::read_from(&mut self.seg_handle, self.version, self.tombstones)?;
+            if after.is_some_and(|after| entry.key.as_slice() <= after) {
+                continue;
+            }
+            if entries.len() == limit {
+                return Ok((entries, true));
+            }
+            entries.push((entry.key.clone(), self.open_entry(entry)?));
+        }
+        Ok((entries, false))
+    }
 
-            let mut offset_bytes: [u8; 8] = [0; 8];
-            idx_cursor.read_exact(&mut offset_bytes)?;
+    // Tag order says nothing about key order, so every entry is opened to find the page
+    fn scan_sealed<V>(&mut self, after: Option<&[u8]>, limit: usize) -> Result<ScanBatch<V>>
+    where
+        V: DeserializeOwned,
+    {
+        let mut plains: Vec<PlainEntry> = self
+            .scan_plain()?
+            .into_iter()
+            .filter(|plain| after.is_none_or(|after| plain.key.as_slice() > after))
+            .collect();
+        plains.sort_by(|a, b| a.key.cmp(&b.key));
+        let more = plains.len() > limit;
+        plains.truncate(limit);
 
-            let offset = u64::from_be_bytes(offset_bytes);
-            self.idx
-                .insert(String::from_utf8(key_bytes)?.into(), offset);
+        let entries = plains
+            .into_iter()
+            .map(|plain| Ok((plain.key.clone(), decode_plain(&self.blobs, plain)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((entries, more))
+    }
+
+    // Every entry in file order, authenticated but with values left encoded and blobs unread
+    pub fn scan_plain(&mut self) -> Result<Vec<PlainEntry>> {
+        self.seg_handle.seek(std::io::SeekFrom::Start(0))?;
+        let mut entries = Vec::new();
+        while self.seg_handle.stream_position()? < self.idx_offset {
+            let entry = RawEntry::read_from(&mut self.seg_handle, self.version, self.tombstones)?;
+            entries.push(open_plain(&self.decrypter, self.compression, entry)?);
         }
-        Ok(())
+        Ok(entries)
+    }
+
+    fn open_entry<V>(&self, entry: RawEntry) -> Result<StoredValue<V>>
+    where
+        V: DeserializeOwned,
+    {
+        open_entry(&self.decrypter, self.compression, &self.blobs, entry)
     }
 
-    fn parse_footer(seg_file: &mut File) -> Result<(u64, u64, SaltString)> {
+    pub fn parse_footer(seg_file: &mut File) -> Result<Footer> {
         let seg_size = seg_file.metadata()?.len();
+        if seg_size < FOOTER_SIZE as u64 {
+            return Err(Error::msg("segment: file too short for a footer"));
+        }
+
+        let mut version = 1;
+        let mut footer_offset = seg_size - FOOTER_SIZE as u64;
+        if seg_size >= (FOOTER_SIZE + TRAILER_SIZE) as u64 {
+            let mut trailer: [u8; TRAILER_SIZE] = [0; TRAILER_SIZE];
+            read_at(seg_file, &mut trailer, seg_size - TRAILER_SIZE as u64)?;
+            if &trailer[4..] == SEGMENT_MAGIC {
+                version = u32::from_be_bytes(trailer[0..4].try_into()?);
+                footer_offset -= TRAILER_SIZE as u64;
+            }
+        }
+        if version > SEGMENT_VERSION {
+            return Err(Error::msg(format!(
+                "segment: unsupported version {version}"
+            )));
+        }
+        // Note: the codec id, flags and wrapped key sit between the footer and the trailer
+        let mut extra: [u8; 2 + WRAPPED_KEY_LEN] = [0; 2 + WRAPPED_KEY_LEN];
+        let extra_len = match version {
+            3 => 1,
+            4 => 2,
+            5.. => 2 + WRAPPED_KEY_LEN,
+            _ => 0,
+        };
+        footer_offset = footer_offset
+            .checked_sub(extra_len as u64)
+            .ok_or_else(|| Error::msg("segment: file too short for a footer"))?;
+        read_exact_at(
+            seg_file,
+            &mut extra[..extra_len],
+            footer_offset + FOOTER_SIZE as u64,
+        )?;
+        let compression = Compression::from_id(extra[0])?;
+        if extra[1] & !(FOOTER_SEALED_KEYS | FOOTER_WRAPPED_KEY | FOOTER_TOMBSTONES) != 0 {
+            return Err(Error::msg("segment: unknown footer flags"));
+        }
 
         let mut footer_bytes: [u8; FOOTER_SIZE] = [0; FOOTER_SIZE];
-        let footer_offset = (seg_size as usize - FOOTER_SIZE) as u64;
-        seg_file.seek_read(&mut footer_bytes[0..FOOTER_SIZE], footer_offset)?;
+        read_at(seg_file, &mut footer_bytes[0..FOOTER_SIZE], footer_offset)?;
 
         let idx_offset = u64::from_be_bytes(footer_bytes[0..8].try_into()?);
         let idx_size = u64::from_be_bytes(footer_bytes[8..16].try_into()?);
 
-        let salt_bytes = &footer_bytes[16..];
-        let salt = DefaultDecrypter::encode_salt_string(salt_bytes)?;
+        let (key, key_offset) = match extra[1] & FOOTER_WRAPPED_KEY != 0 {
+            true => (
+                FileKey::Wrapped(extra[2..].try_into()?),
+                footer_offset + FOOTER_SIZE as u64 + 2,
+            ),
+            false => (
+                FileKey::Salt(footer_bytes[16..].try_into()?),
+                footer_offset + 16,
+            ),
+        };
+
+        Ok(Footer {
+            idx_offset,
+            idx_size,
+            key,
+            key_offset,
+            version,
+            compression,
+            sealed_keys: extra[1] & FOOTER_SEALED_KEYS != 0,
+            tombstones: extra[1] & FOOTER_TOMBSTONES != 0,
+            footer_offset,
+        })
+    }
+}
+
+// An authenticated entry whose plaintext is an encoded value, a `BlobRef` or empty for a tombstone.
+// Sealed keys come out unwrapped and values decompressed.
+#[derive(Debug, Clone)]
+pub struct PlainEntry {
+    pub key: Vec<u8>,
+    pub expires_at: Option<u64>,
+    pub blob: bool,
+    pub tombstone: bool,
+    pub plaintext: Vec<u8>,
+}
 
-        Ok((idx_offset, idx_size, salt))
+pub fn open_plain(
+    decrypter: &DefaultDecrypter,
+    compression: Compression,
+    mut entry: RawEntry,
+) -> Result<PlainEntry> {
+    let mut aad = entry.aad();
+    let mut plaintext = decrypter
+        .decrypt(&mut entry.sealed, entry.nonce, &mut aad)?
+        .to_vec();
+    if entry.padded {
+        plaintext = unpad(&plaintext)?.to_vec();
+    }
+    if entry.sealed_key {
+        (entry.key, plaintext) = unwrap_key(&plaintext)?;
     }
+    let tombstone = entry
+        .tombstone
+        .unwrap_or(!entry.blob && plaintext.is_empty());
+    if !entry.blob && !tombstone {
+        plaintext = compression.decompress(&plaintext)?;
+    }
+    Ok(PlainEntry {
+        key: entry.key,
+        expires_at: entry.expires_at,
+        blob: entry.blob,
+        tombstone,
+        plaintext,
+    })
+}
+
+pub fn open_entry<V>(
+    decrypter: &DefaultDecrypter,
+    compression: Compression,
+    blobs: &BlobStore,
+    entry: RawEntry,
+) -> Result<StoredValue<V>>
+where
+    V: DeserializeOwned,
+{
+    decode_plain(blobs, open_plain(decrypter, compression, entry)?)
+}
+
+pub fn decode_plain<V>(blobs: &BlobStore, plain: PlainEntry) -> Result<StoredValue<V>>
+where
+    V: DeserializeOwned,
+{
+    let encoded = if plain.blob {
+        blobs.read(&plain.key, BlobRef::decode(&plain.plaintext)?)?
+    } else {
+        plain.plaintext
+    };
+    let value = if plain.tombstone {
+        None
+    } else {
+        let value: (V, usize) =
+            bincode::serde::decode_from_slice(&encoded, bincode::config::standard())?;
+        Some(value.0)
+    };
+    Ok(StoredValue {
+        value,
+        expires_at: plain.expires_at,
+    })
+}
+
+fn parse_index(mut idx_cursor: &[u8]) -> Result<IndexEntries> {
+    let mut index = Vec::new();
+    while !idx_cursor.is_empty() {
+        let mut key_len_bytes: [u8; 4] = [0; 4];
+        idx_cursor.read_exact(&mut key_len_bytes)?;
+
+        let key_len = u32::from_be_bytes(key_len_bytes) as usize;
+        if key_len > idx_cursor.len() {
+            return Err(Error::msg("segment: index key overruns the index"));
+        }
+        let mut key_bytes = vec![0; key_len];
+        idx_cursor.read_exact(&mut key_bytes)?;
+
+        let mut offset_bytes: [u8; 8] = [0; 8];
+        idx_cursor.read_exact(&mut offset_bytes)?;
+        index.push((key_bytes, u64::from_be_bytes(offset_bytes)));
+    }
+    Ok(index)
+}
+
+// The index a footer points at, read without the password
+pub fn read_index(seg_file: &File, footer: &Footer) -> Result<IndexEntries> {
+    let seg_size = seg_file.metadata()?.len();
+    if footer
+        .idx_offset
+        .checked_add(footer.idx_size)
+        .is_none_or(|end| end > seg_size)
+    {
+        return Err(Error::msg("segment: index lies outside the file"));
+    }
+    let mut idx = vec![0; footer.idx_size as usize];
+    read_exact_at(seg_file, &mut idx, footer.idx_offset)?;
+    parse_index(&idx)
+}
+
+// Entries of the data region in file order with their offsets, read without the password
+#[derive(Debug, Default)]
+pub struct EntryScan {
+    pub entries: Vec<(u64, RawEntry)>,
+    // Offset and cause where parsing stopped before the end of the data region
+    pub error: Option<(u64, Error)>,
+}
+
+pub fn read_entries(seg_file: &File, footer: &Footer) -> Result<EntryScan> {
+    let seg_size = seg_file.metadata()?.len();
+    if footer.idx_offset > seg_size {
+        return Err(Error::msg("segment: data region lies outside the file"));
+    }
+    let mut data = vec![0; footer.idx_offset as usize];
+    read_exact_at(seg_file, &mut data, 0)?;
+
+    let mut scan = EntryScan::default();
+    let mut cursor = &data[..];
+    while !cursor.is_empty() {
+        let offset = (data.len() - cursor.len()) as u64;
+        match RawEntry::read_from(&mut cursor, footer.version, footer.tombstones) {
+            Ok(entry) => scan.entries.push((offset, entry)),
+            Err(err) => {
+                scan.error = Some((offset, err));
+                break;
+            }
+        }
+    }
+    Ok(scan)
+}
+
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    while !buf.is_empty() {
+        let n = read_at(file, buf, offset)?;
+        if n == 0 {
+            return Err(Error::msg("segment: unexpected end of file"));
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
 }
@@ -0,0 +1,476 @@
+use crate::proto::{Request, Response, read_frame};
+use crate::resp::{Reply, read_command};
+use crate::store::KvStore;
+use crate::tls::Stream;
+use anyhow::Result;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+const MAX_CURSORS: usize = 4096;
+
+// SCAN cursors are u64 numbers on the wire, each one stands for the last key a page covered
+// Note: only the newest MAX_CURSORS are kept, an older one is rejected and the scan starts over
+#[derive(Default)]
+struct Cursors {
+    next_id: u64,
+    keys: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+impl Cursors {
+    fn register(&mut self, key: Vec<u8>) -> u64 {
+        self.next_id += 1;
+        if self.order.len() == MAX_CURSORS
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.keys.remove(&oldest);
+        }
+        self.keys.insert(self.next_id, key);
+        self.order.push_back(self.next_id);
+        self.next_id
+    }
+
+    fn resolve(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.keys.get(&cursor).cloned()
+    }
+}
+
+struct Server {
+    store: Arc<KvStore<Vec<u8>>>,
+    cursors: Mutex<Cursors>,
+}
+
+// RESP on the listener until it fails, one thread per client
+// With a TLS config every client has to complete a mutually authenticated handshake first
+pub fn serve(
+    store: Arc<KvStore<Vec<u8>>>,
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<()> {
+    let server = Arc::new(Server {
+        store,
+        cursors: Mutex::new(Cursors::default()),
+    });
+    accept(listener, tls, move |stream| {
+        server.handle_connection(stream)
+    })
+}
+
+// The framed binary protocol of `proto`, as spoken by `client::Client`
+pub fn serve_native(
+    store: Arc<KvStore<Vec<u8>>>,
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<()> {
+    accept(listener, tls, move |stream| handle_native(&store, stream))
+}
+
+fn accept<F>(listener: TcpListener, tls: Option<Arc<ServerConfig>>, handler: F) -> Result<()>
+where
+    F: Fn(Box<dyn Stream>) -> Result<()> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let handler = handler.clone();
+        let tls = tls.clone();
+        thread::spawn(move || -> Result<()> {
+            let stream = wrap(stream, tls)?;
+            handler(stream)
+        });
+    }
+    Ok(())
+}
+
+fn wrap(stream: TcpStream, tls: Option<Arc<ServerConfig>>) -> Result<Box<dyn Stream>> {
+    stream.set_nodelay(true)?;
+    Ok(match tls {
+        // Note: the handshake runs on the first read, a client without a valid certificate fails there
+        Some(config) => Box::new(StreamOwned::new(ServerConnection::new(config)?, stream)),
+        None => Box::new(stream),
+    })
+}
+
+fn handle_native(store: &KvStore<Vec<u8>>, stream: Box<dyn Stream>) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut out = Vec::new();
+
+    while let Some(payload) = read_frame(&mut reader)? {
+        let (id, request) = match Request::decode(&payload) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                // Note: the stream can not be trusted past a malformed frame
+                out.extend_from_slice(&Response::Error(e.to_string()).encode(0));
+                break;
+            }
+        };
+        let response =
+            execute_native(store, request).unwrap_or_else(|e| Response::Error(e.to_string()));
+        out.extend_from_slice(&response.encode(id));
+
+        if reader.buffer().is_empty() {
+            write_out(reader.get_mut(), &mut out)?;
+        }
+    }
+    write_out(reader.get_mut(), &mut out)
+}
+
+fn write_out(stream: &mut Box<dyn Stream>, out: &mut Vec<u8>) -> Result<()> {
+    if !out.is_empty() {
+        stream.write_all(out)?;
+        out.clear();
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+fn execute_native(store: &KvStore<Vec<u8>>, request: Request) -> Result<Response> {
+    let response = match request {
+        Request::Ping => Response::Pong,
+        Request::Get { key } => Response::Value(store.get(&key)?),
+        Request::Put {
+            key,
+            value,
+            ttl: None,
+        } => {
+            store.put(&key, value)?;
+            Response::Done
+        }
+        Request::Put {
+            key,
+            value,
+            ttl: Some(ttl),
+        } => {
+            store.put_with_ttl(&key, value, ttl)?;
+            Response::Done
+        }
+        Request::Delete { key } => {
+            store.delete(&key)?;
+            Response::Done
+        }
+        Request::Expire { key, ttl } => Response::Existed(store.expire(&key, ttl)?),
+        Request::Scan { after, limit } => {
+            let page = store.scan(after.as_deref(), limit as usize)?;
+            Response::Page {
+                entries: page.entries,
+                next: page.next,
+            }
+        }
+        Request::Flush => {
+            store.flush()?;
+            Response::Done
+        }
+        Request::Stats => Response::Stats(store.stats()),
+    };
+    Ok(response)
+}
+
+impl Server {
+    fn handle_connection(&self, stream: Box<dyn Stream>) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut out = Vec::new();
+
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(e) => {
+                    Reply::error(format!("ERR {e}")).write_to(&mut out)?;
+                    break;
+                }
+            };
+
+            let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+            let reply = self
+                .execute(&name, &args[1..])
+                .unwrap_or_else(|e| Reply::error(format!("ERR {e}")));
+            reply.write_to(&mut out)?;
+
+            if name == "QUIT" {
+                break;
+            }
+            // Note: pipelined commands are answered in one write once the input runs dry
+            if reader.buffer().is_empty() {
+                write_out(reader.get_mut(), &mut out)?;
+            }
+        }
+        write_out(reader.get_mut(), &mut out)
+    }
+
+    fn execute(&self, name: &str, args: &[Vec<u8>]) -> Result<Reply> {
+        let store = &self.store;
+        let reply = match (name, args) {
+            ("PING", []) => Reply::Simple("PONG".to_string()),
+            ("PING", [message]) => Reply::bulk(message.clone()),
+            ("QUIT", []) => Reply::ok(),
+            ("COMMAND", _) => Reply::Array(Vec::new()),
+            ("GET", [key]) => match store.get(key)? {
+                Some(value) => Reply::bulk(value),
+                None => Reply::nil(),
+            },
+            ("SET", [key, value, options @ ..]) => {
+                let value = value.clone();
+                match options {
+                    [] => store.put(key, value)?,
+                    [unit, amount] => {
+                        let amount = integer(amount)?;
+                        if amount <= 0 {
+                            return Ok(Reply::error("ERR invalid expire time in 'set' command"));
+                        }
+                        let ttl = match utf8(unit)?.to_ascii_uppercase().as_str() {
+                            "EX" => Duration::from_secs(amount as u64),
+                            "PX" => Duration::from_millis(amount as u64),
+                            _ => return Ok(Reply::error("ERR syntax error")),
+                        };
+                        store.put_with_ttl(key, value, ttl)?;
+                    }
+                    _ => return Ok(Reply::error("ERR syntax error")),
+                }
+                Reply::ok()
+            }
+            ("DEL", keys) if !keys.is_empty() => {
+                let mut removed = 0;
+                for key in keys {
+                    if store.get(key)?.is_some() {
+                        store.delete(key)?;
+                        removed += 1;
+                    }
+                }
+                Reply::Integer(removed)
+            }
+            ("EXPIRE", [key, seconds]) => {
+                let seconds = integer(seconds)?;
+                let existed = if seconds <= 0 {
+                    let existed = store.get(key)?.is_some();
+                    if existed {
+                        store.delete(key)?;
+                    }
+                    existed
+                } else {
+                    store.expire(key, Duration::from_secs(seconds as u64))?
+                };
+                Reply::Integer(existed as i64)
+            }
+            ("SCAN", [cursor, options @ ..]) => self.scan(cursor, options)?,
+            ("INFO", [] | [_]) => {
+                let stats = store.stats();
+                let info = format!(
+                    "# Store\r\nsegments:{}\r\nmemtable_entries:{}\r\nfrozen_memtables:{}\r\n\
+                     # WAL\r\nwal_id:{}\r\nwal_appended_bytes:{}\r\nwal_synced_bytes:{}\r\nwal_syncs:{}\r\n",
+                    stats.segments,
+                    stats.memtable_entries,
+                    stats.frozen_memtables,
+                    stats.wal_id,
+                    stats.wal.appended_bytes,
+                    stats.wal.synced_bytes,
+                    stats.wal.syncs,
+                );
+                Reply::bulk(info)
+            }
+            ("PING" | "QUIT" | "GET" | "SET" | "DEL" | "EXPIRE" | "SCAN" | "INFO", _) => {
+                Reply::error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name.to_ascii_lowercase()
+                ))
+            }
+            _ => Reply::error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )),
+        };
+        Ok(reply)
+    }
+
+    fn scan(&self, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in options.chunks(2) {
+            let [name, value] = option else {
+                return Ok(Reply::error("ERR syntax error"));
+            };
+            match utf8(name)?.to_ascii_uppercase().as_str() {
+                "MATCH" => pattern = Some(value.as_slice()),
+                "COUNT" => match integer(value)? {
+                    n if n > 0 => count = n as usize,
+                    _ => return Ok(Reply::error("ERR syntax error")),
+                },
+                _ => return Ok(Reply::error("ERR syntax error")),
+            }
+        }
+
+        let after = match utf8(cursor)?.parse::<u64>() {
+            Ok(0) => None,
+            Ok(cursor) => match self.cursors.lock().expect("cursor lock").resolve(cursor) {
+                Some(key) => Some(key),
+                None => return Ok(Reply::error("ERR invalid cursor")),
+            },
+            Err(_) => return Ok(Reply::error("ERR invalid cursor")),
+        };
+
+        let page = self.store.scan(after.as_deref(), count)?;
+        let next = match page.next {
+            Some(key) => self.cursors.lock().expect("cursor lock").register(key),
+            None => 0,
+        };
+        let keys = page
+            .entries
+            .into_iter()
+            .filter(|(key, _)| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|(key, _)| Reply::bulk(key))
+            .collect();
+
+        Ok(Reply::Array(vec![
+            Reply::bulk(next.to_string()),
+            Reply::Array(keys),
+        ]))
+    }
+}
+
+fn utf8(arg: &[u8]) -> Result<&str> {
+    std::str::from_utf8(arg).map_err(|_| anyhow::anyhow!("argument is not valid UTF-8"))
+}
+
+fn integer(arg: &[u8]) -> Result<i64> {
+    utf8(arg)?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("value is not an integer or out of range"))
+}
+
+// Redis style globs: `*`, `?`, `[abc]`, `[a-z]`, `[^a]` and `\` escapes
+// Note: every other element matches exactly one byte, so on a mismatch only the last `*` has to
+// take one more byte, which keeps matching linear in the pattern times the text
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The pattern after the last `*` and where in the text that `*` stops for now
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(next) = match_byte(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+        } else if let Some((after_star, covered)) = star {
+            p = after_star;
+            t = covered + 1;
+            star = Some((after_star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// Where the pattern continues if the element at `p` matches `c`
+fn match_byte(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern.get(p..)? {
+        [b'?', ..] => Some(p + 1),
+        [b'[', rest @ ..] => {
+            let end = rest.iter().position(|&b| b == b']')?;
+            let (class, negated) = match rest[..end].strip_prefix(b"^") {
+                Some(class) => (class, true),
+                None => (&rest[..end], false),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (matched != negated).then_some(p + end + 2)
+        }
+        [b'\\', escaped, ..] => (c == *escaped).then_some(p + 2),
+        [b, ..] => (c == *b).then_some(p + 1),
+        [] => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::store::Options;
+    use crate::testutil;
+
+    fn scan_page(server: &Server, cursor: &str) -> (String, Vec<Vec<u8>>) {
+        let reply = server
+            .scan(cursor.as_bytes(), &[b"COUNT".to_vec(), b"2".to_vec()])
+            .unwrap();
+        let Reply::Array(parts) = reply else {
+            panic!("SCAN replied {reply:?}");
+        };
+        let [Reply::Bulk(Some(next)), Reply::Array(keys)] = parts.as_slice() else {
+            panic!("SCAN replied {parts:?}");
+        };
+        let keys = keys
+            .iter()
+            .map(|key| match key {
+                Reply::Bulk(Some(key)) => key.clone(),
+                key => panic!("SCAN key {key:?}"),
+            })
+            .collect();
+        (String::from_utf8(next.clone()).unwrap(), keys)
+    }
+
+    #[test]
+    fn scan_cursors_are_u64_whatever_the_key_length() {
+        let dir = testutil::temp_dir("server");
+        let store = KvStore::open(&dir, Options::with_secret(Secret::Key([1; 32]))).unwrap();
+        let long_key = |n: u8| vec![n; 4096];
+        for n in 0..5 {
+            store.put(&long_key(n), vec![n]).unwrap();
+        }
+        let server = Server {
+            store,
+            cursors: Mutex::new(Cursors::default()),
+        };
+
+        let (mut cursor, mut keys) = (String::from("0"), Vec::new());
+        loop {
+            let (next, page) = scan_page(&server, &cursor);
+            keys.extend(page);
+            if next == "0" {
+                break;
+            }
+            next.parse::<u64>().expect("cursor fits in a u64");
+            cursor = next;
+        }
+        assert_eq!(keys, (0..5).map(long_key).collect::<Vec<_>>());
+        assert!(matches!(
+            server.scan(b"99999999999999999999", &[]).unwrap(),
+            Reply::Error(_)
+        ));
+        server.store.flush().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_newest_cursors_are_kept() {
+        let mut cursors = Cursors::default();
+        let first = cursors.register(b"first".to_vec());
+        let ids: Vec<u64> = (0..MAX_CURSORS)
+            .map(|n| cursors.register(n.to_be_bytes().to_vec()))
+            .collect();
+
+        assert_eq!(cursors.resolve(first), None);
+        assert_eq!(cursors.resolve(ids[0]), Some(0usize.to_be_bytes().to_vec()));
+        assert_eq!(cursors.keys.len(), MAX_CURSORS);
+        assert!(!ids.contains(&0));
+    }
+}
@@ -1,303 +1,1257 @@
 use crate::{
-    FOOTER_SIZE, INDEX_DENSITY, MAX_MEMTABLE,
+    BLOB_THRESHOLD, MAX_MEMTABLE,
+    archive::{Retention, WalArchive},
+    blob::{BlobRef, BlobStore, list_blob_ids},
+    compression::Compression,
     encryption::{
-        DecryptError, Decrypter, DefaultDecrypter, DefaultEncrypter, EncryptError, Encrypter,
+        DecryptError, DefaultDecrypter, DefaultEncrypter, EncryptError, Encrypter, Secret,
+    },
+    header::{self, HEADER_FILE, SlotKind, StoreHeader, StoreSettings},
+    keyring::{FileKey, Keyring},
+    padding::Padding,
+    recovery::{RecoveryTarget, RestoreReport, logs_since, read_checkpoint, write_checkpoint},
+    rekey::{self, PasswordChange, RekeyProgress, RekeyReport},
+    segment::{
+        PlainEntry, RawEntry, ScanBatch, SegmentBuilder, SegmentFile, SegmentIter, wrap_key,
+    },
+    verify::{VerifyReport, verify_segment, verify_wal},
+    wal::{
+        Durability, LEGACY_LOG, RecordKind, Recovery, WAL_VERSION, Wal, WalRecord, WalStats,
+        encode_log, list_wal_ids, now_millis, read_legacy_log, read_records, sync_dir, wal_path,
     },
-    segment::SegmentIter,
 };
 use anyhow::{Error, Result};
-use base64::{Engine, prelude::BASE64_STANDARD};
 use core::fmt;
+use serde::{Serialize, de::DeserializeOwned};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{Debug, Display, Formatter},
     fs::{self, File, OpenOptions},
-    io::{Read, Write, stdin},
-    ops::Add,
+    io::{ErrorKind, Write},
+    ops::Bound,
     path::{Path, PathBuf},
-    str::FromStr,
     sync::{
-        Arc, Mutex,
-        mpsc::{self, Receiver, SendError, Sender},
+        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
     },
+    thread,
+    time::Duration,
 };
 
+// Keys are ordered by their bytes
+type Memtable<V> = BTreeMap<Vec<u8>, StoredValue<V>>;
+
+// Note: a `None` value is a tombstone, it hides older values of the key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredValue<V> {
+    pub value: Option<V>,
+    pub expires_at: Option<u64>,
+}
+
+impl<V> StoredValue<V> {
+    pub fn live(self, now: u64) -> Option<V> {
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return None;
+        }
+        self.value
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanPage<V> {
+    pub entries: Vec<(Vec<u8>, V)>,
+    // Keys after this one have not been looked at yet
+    pub next: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreStats {
+    pub segments: usize,
+    pub memtable_entries: usize,
+    pub frozen_memtables: usize,
+    pub wal_id: usize,
+    pub wal: WalStats,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactReport {
+    pub segments_in: usize,
+    pub entries_kept: usize,
+    pub entries_dropped: usize,
+    pub blobs_relocated: usize,
+    pub blob_files_removed: usize,
+    pub blob_bytes_freed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub secret: Secret,
+    pub durability: Durability,
+    pub retention: Retention,
+    // Encoded values longer than this are flushed to blob files, segments keep a pointer
+    pub blob_threshold: usize,
+    // Codec for values written to segments and blobs from now on, the log is not compressed
+    pub compression: Compression,
+    // Segments and the log get an HMAC tag in place of each key, the key is sealed with the value.
+    // Note: recorded in the store header, later runs keep to it, see `keep_settings`
+    pub encrypt_keys: bool,
+    // Applied to everything sealed from now on, tombstones included. `None` keeps the policy the
+    // store header records.
+    pub padding: Padding,
+}
+
+impl Options {
+    pub fn new(password: String) -> Self {
+        Self::with_secret(Secret::Passphrase(password))
+    }
+
+    pub fn with_secret(secret: Secret) -> Self {
+        Self {
+            secret,
+            durability: Durability::default(),
+            retention: Retention::default(),
+            blob_threshold: BLOB_THRESHOLD,
+            compression: Compression::default(),
+            encrypt_keys: false,
+            padding: Padding::default(),
+        }
+    }
+}
+
+// A full memtable waiting to be flushed, still readable until its segment is written
+#[derive(Debug)]
+struct FrozenMemtable<V> {
+    wal: Arc<Wal>,
+    table: Memtable<V>,
+}
+
 #[derive(Debug)]
 pub struct KvStore<V> {
-    memtable: Arc<Mutex<BTreeMap<String, V>>>,
-    encypter_guard: Arc<Mutex<DefaultEncrypter>>,
-    seq_num: Arc<Mutex<usize>>,
-    log_handle: Arc<Mutex<File>>,
+    memtable: Arc<Mutex<Memtable<V>>>,
+    frozen: Arc<Mutex<VecDeque<Arc<FrozenMemtable<V>>>>>,
+    flushed: Arc<Condvar>,
+    flusher_stopped: Arc<AtomicBool>,
+    // Ids of the flushed segments, oldest first
+    segments: Arc<RwLock<Vec<usize>>>,
+    // Serializes flushing and compaction, the only writers of segment files
+    segment_writer: Mutex<()>,
+    // Writers hold the read side from WAL append until memtable insert, freezing takes the write side
+    wal: Arc<RwLock<Arc<Wal>>>,
+    durability: Durability,
+    archive: WalArchive,
+    blobs: Arc<BlobStore>,
+    blob_threshold: usize,
+    compression: Compression,
+    encrypt_keys: bool,
+    padding: Padding,
 
-    password: String,
+    keyring: Arc<Keyring>,
     curr_dir: PathBuf,
 
-    flush_tx: Sender<BTreeMap<String, V>>,
-    flush_rx: Arc<Mutex<Receiver<BTreeMap<String, V>>>>,
+    flush_tx: Sender<Arc<FrozenMemtable<V>>>,
+    flush_rx: Arc<Mutex<Receiver<Arc<FrozenMemtable<V>>>>>,
 }
 
 impl<V> KvStore<V>
 where
-    V: bincode::Decode<()> + FromStr + bincode::Encode + Send + Sync + Display + 'static,
-    <V as FromStr>::Err: Debug,
+    V: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
 {
-    pub fn new(password: String, latest_segment: usize, curr_dir: PathBuf) -> Result<Self> {
-        let encrypter = DefaultEncrypter::new(password.to_owned())?;
-        let (tx, rx) = mpsc::channel::<BTreeMap<String, V>>();
+    // Recovers the WALs in `path` and starts the background flush thread
+    pub fn open(path: impl Into<PathBuf>, mut options: Options) -> Result<Arc<Self>> {
+        let curr_dir = path.into();
+        fs::create_dir_all(&curr_dir)?;
+        let keyring = header::unlock(&curr_dir, &options.secret)?;
+        rekey::recover(&curr_dir)?;
+        recover_compaction(&curr_dir)?;
+        keep_settings(&curr_dir, &mut options)?;
+        let segments = list_segment_ids(&curr_dir)?;
+
+        let store = Arc::new(Self::new(segments, curr_dir, options, keyring)?);
+        store.sync_wal()?;
+        store.replay_legacy_log()?;
+
+        let bg_store = Arc::clone(&store);
+        thread::spawn(move || {
+            if let Err(err) = bg_store.run_bg_thread() {
+                eprintln!("{err}")
+            }
+        });
+        Ok(store)
+    }
+
+    pub fn new(
+        segments: Vec<usize>,
+        curr_dir: PathBuf,
+        options: Options,
+        keyring: Keyring,
+    ) -> Result<Self> {
+        let keyring = Arc::new(keyring);
+        let (tx, rx) = mpsc::channel::<Arc<FrozenMemtable<V>>>();
+
+        let active_id = list_wal_ids(&curr_dir)?.last().copied().unwrap_or(0);
+
+        // Note: a log in an older format is replayed as a frozen memtable instead of appended to
+        let mut wal = Wal::open(&curr_dir, active_id, options.durability, &keyring)?;
+        if wal.version() < WAL_VERSION {
+            wal = Wal::open(&curr_dir, active_id + 1, options.durability, &keyring)?;
+        }
+
         Ok(Self {
-            log_handle: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .append(true)
-                    .create(true)
-                    .open(curr_dir.join(format!("wal.log")).as_path())?,
-            )),
-            seq_num: Arc::new(Mutex::new(latest_segment)),
+            wal: Arc::new(RwLock::new(wal)),
+            durability: options.durability,
+            archive: WalArchive::new(curr_dir.join("archive"), options.retention),
+            blobs: Arc::new(BlobStore::new(&curr_dir, keyring.clone())?),
+            blob_threshold: options.blob_threshold,
+            compression: options.compression,
+            encrypt_keys: options.encrypt_keys,
+            padding: options.padding,
+            segments: Arc::new(RwLock::new(segments)),
+            segment_writer: Mutex::new(()),
             memtable: Arc::new(Mutex::new(BTreeMap::new())),
+            frozen: Arc::new(Mutex::new(VecDeque::new())),
+            flushed: Arc::new(Condvar::new()),
+            flusher_stopped: Arc::new(AtomicBool::new(false)),
             flush_rx: Arc::new(Mutex::new(rx)),
-            encypter_guard: Arc::new(Mutex::new(encrypter)),
-            password: password,
-            curr_dir: curr_dir,
+            keyring,
+            curr_dir,
             flush_tx: tx,
         })
     }
 
-    pub fn run(&self) -> Result<()>
-    where
-        <V as FromStr>::Err: std::error::Error,
-        <V as FromStr>::Err: Send,
-        <V as FromStr>::Err: Sync,
-    {
-        if let Ok(file) = File::open(self.curr_dir.join("wal.log")) {
-            self.sync_wal(file)?
-        }
+    pub fn get(&self, key: &[u8]) -> Result<Option<V>> {
+        Ok(self
+            .get_entry(key)?
+            .and_then(|entry| entry.live(now_millis())))
+    }
 
-        let lines = stdin().lines();
-        for line in lines {
-            let line = line?;
-            let cmd_seq: Vec<_> = line.split_whitespace().collect();
+    pub fn put(&self, key: &[u8], value: V) -> Result<()> {
+        self.write(
+            key.to_vec(),
+            StoredValue {
+                value: Some(value),
+                expires_at: None,
+            },
+        )
+    }
 
-            match cmd_seq[0] {
-                "SET" => {
-                    assert!(cmd_seq[1..].len() == 2);
-                    let (k, v) = (cmd_seq[1], cmd_seq[2]);
-                    let (key_len, value_len) = (k.len(), v.len());
+    pub fn put_with_ttl(&self, key: &[u8], value: V, ttl: Duration) -> Result<()> {
+        let expires_at = u64::try_from(ttl.as_millis())
+            .ok()
+            .and_then(|ttl| now_millis().checked_add(ttl))
+            .ok_or(InvalidExpireTime)?;
+        self.write(
+            key.to_vec(),
+            StoredValue {
+                value: Some(value),
+                expires_at: Some(expires_at),
+            },
+        )
+    }
 
-                    assert!(key_len <= u8::MAX as usize);
-                    assert!(value_len <= u8::MAX as usize);
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write(
+            key.to_vec(),
+            StoredValue {
+                value: None,
+                expires_at: None,
+            },
+        )
+    }
 
-                    self.write_wal(k.to_string(), v.parse()?)?;
-                    self.insert_parsed(k.to_string(), v.to_string())?;
+    // Re-writes the current value with a deadline, false if the key does not exist
+    // Note: not atomic with respect to a concurrent put of the same key
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        let Some(value) = self.get(key)? else {
+            return Ok(false);
+        };
+        self.put_with_ttl(key, value, ttl)?;
+        Ok(true)
+    }
 
-                    let tx: mpsc::Sender<BTreeMap<String, V>> = self.flush_tx.clone();
-                    let mut memtable = self.memtable.lock().expect("get mut");
-                    if memtable.len() >= MAX_MEMTABLE {
-                        let flush_table: BTreeMap<String, V> = std::mem::take(&mut memtable);
-                        tx.send(flush_table)?;
-                    }
+    // Live entries in key order after `after`, looking at no more than `limit` keys per source
+    pub fn scan(&self, after: Option<&[u8]>, limit: usize) -> Result<ScanPage<V>> {
+        let lower = after.map_or(Bound::Unbounded, |key| Bound::Excluded(key.to_vec()));
+        let limit = limit.max(1);
 
-                    println!("SET done")
-                }
-                "GET" => {
-                    assert!(cmd_seq[1..].len() == 1);
-                    if let Some(val) = self.memtable.lock().expect("get lock").get(cmd_seq[1]) {
-                        println!("GET -> {}", val)
-                    } else {
-                        let curr_seg = self.seq_num.lock().expect("lock seq num").clone();
-
-                        let seg_iter = SegmentIter::new(
-                            (0..curr_seg).into_iter().collect(),
-                            self.curr_dir.clone(),
-                            self.password.to_owned(),
-                        );
-                        if let Ok(Some(value)) = seg_iter.find_key_in_segments(cmd_seq[1]) {
-                            println!("GET -> {}", value)
-                        } else {
-                            println!("Not found")
-                        }
-                    }
+        // Newest source first, the first entry seen for a key wins
+        let mut sources: Vec<ScanBatch<V>> = Vec::new();
+        let from_table = |table: &Memtable<V>| {
+            let mut range = table.range((lower.clone(), Bound::Unbounded));
+            let entries: Vec<_> = range
+                .by_ref()
+                .take(limit)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            (entries, range.next().is_some())
+        };
+        sources.push(from_table(&self.memtable.lock().expect("get lock")));
+        for frozen in self.frozen.lock().expect("frozen lock").iter().rev() {
+            sources.push(from_table(&frozen.table));
+        }
+
+        // Note: holding the read side keeps compaction from deleting segments mid-iteration
+        let segments = self.segments.read().expect("segments lock");
+        let seg_iter = SegmentIter::new(
+            segments.clone(),
+            self.curr_dir.clone(),
+            self.keyring.clone(),
+            self.blobs.clone(),
+        );
+        for seg in seg_iter {
+            sources.push(seg?.scan(after, limit)?);
+        }
+
+        drop(segments);
+
+        // Only keys up to the smallest last key of a truncated source are complete
+        let next = sources
+            .iter()
+            .filter(|(_, truncated)| *truncated)
+            .filter_map(|(entries, _)| entries.last().map(|(k, _)| k.clone()))
+            .min();
+
+        let mut merged: Memtable<V> = BTreeMap::new();
+        for (entries, _) in sources {
+            for (k, v) in entries {
+                if next.as_ref().is_none_or(|next| k <= *next) {
+                    merged.entry(k).or_insert(v);
                 }
-                _ => return Err(Error::msg("Unknown cmd")),
             }
         }
+
+        let now = now_millis();
+        Ok(ScanPage {
+            entries: merged
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, v.live(now)?)))
+                .collect(),
+            next,
+        })
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let wal = self.wal.read().expect("wal lock").clone();
+        StoreStats {
+            segments: self.segments.read().expect("segments lock").len(),
+            memtable_entries: self.memtable.lock().expect("get lock").len(),
+            frozen_memtables: self.frozen.lock().expect("frozen lock").len(),
+            wal_id: wal.id(),
+            wal: wal.stats(),
+        }
+    }
+
+    fn get_entry(&self, key: &[u8]) -> Result<Option<StoredValue<V>>> {
+        if let Some(entry) = self.memtable.lock().expect("get lock").get(key) {
+            return Ok(Some(entry.clone()));
+        }
+        if let Some(entry) = self.find_in_frozen(key) {
+            return Ok(Some(entry));
+        }
+
+        // Note: holding the read side keeps compaction from deleting segments mid-iteration
+        let segments = self.segments.read().expect("segments lock");
+        let seg_iter = SegmentIter::new(
+            segments.clone(),
+            self.curr_dir.clone(),
+            self.keyring.clone(),
+            self.blobs.clone(),
+        );
+        seg_iter.find_key_in_segments(key)
+    }
+
+    // Freezes the current memtable and blocks until every frozen memtable is in a segment
+    pub fn flush(&self) -> Result<()> {
+        self.freeze_memtable(true)?;
+        self.drain()
+    }
+
+    // Blocks until the memtables frozen so far are in segments, the active memtable stays in its WAL
+    pub fn drain(&self) -> Result<()> {
+        let frozen = self.frozen.lock().expect("frozen lock");
+        let Some(last) = frozen.back().cloned() else {
+            return Ok(());
+        };
+        let _frozen = self
+            .flushed
+            .wait_while(frozen, |frozen| {
+                frozen.iter().any(|table| Arc::ptr_eq(table, &last))
+                    && !self.flusher_stopped.load(Ordering::SeqCst)
+            })
+            .expect("wait on flush");
+
+        if self.flusher_stopped.load(Ordering::SeqCst) {
+            return Err(Error::msg("flush: background thread stopped"));
+        }
         Ok(())
     }
 
     pub fn run_bg_thread(&self) -> Result<()> {
-        let log_handle_bg = self.log_handle.clone();
-        let seq_bg = self.seq_num.clone();
-        let encrypter_bg = self.encypter_guard.clone();
-
-        for flush_table in self.flush_rx.lock().expect("rx lock").iter() {
-            let mut seq_num = seq_bg.lock().expect("lock seq num");
-            let mut seq_handle = OpenOptions::new().write(true).create(true).open(
-                self.curr_dir
-                    .join(format!("segment_{}.sstable", seq_num))
-                    .as_path(),
-            )?;
-
-            let mut idx = Vec::new();
-            let mut buf = Vec::new();
-
-            for (i, (k, v)) in flush_table.iter().enumerate() {
-                let offset = buf.len() as u64;
-                let (sealed_bytes, nonce) = self.build_entry(&k, &v)?;
-
-                let key_len: u32 = k.as_bytes().len() as u32;
-                let cipher_len: u32 = sealed_bytes.len() as u32;
-
-                buf.extend_from_slice(&key_len.to_be_bytes());
-                buf.extend_from_slice(k.as_bytes());
-                buf.extend_from_slice(&nonce);
-                buf.extend_from_slice(&cipher_len.to_be_bytes());
-                buf.extend_from_slice(sealed_bytes.as_slice());
-
-                if i % (flush_table.len() / INDEX_DENSITY) == 0 {
-                    idx.extend_from_slice(&key_len.to_be_bytes());
-                    idx.extend_from_slice(k.as_bytes());
-                    idx.extend_from_slice(&offset.to_be_bytes());
-                }
+        let result = self.flush_frozen();
+        // Note: the channel never closes while the store is alive, so getting here means an error
+        self.flusher_stopped.store(true, Ordering::SeqCst);
+        self.flushed.notify_all();
+        result
+    }
+
+    fn flush_frozen(&self) -> Result<()> {
+        for frozen in self.flush_rx.lock().expect("rx lock").iter() {
+            let flush_table = &frozen.table;
+            let writer = self.segment_writer.lock().expect("segment writer lock");
+            if flush_table.is_empty() {
+                self.archive.archive(&frozen.wal)?;
+                self.frozen.lock().expect("frozen lock").pop_front();
+                self.flushed.notify_all();
+                continue;
             }
-            let mut footer: Vec<u8> = vec![0; FOOTER_SIZE];
-            footer[0..8].copy_from_slice(&buf.len().to_be_bytes());
-            footer[8..16].copy_from_slice(&idx.len().to_be_bytes());
 
-            let mut salt_bytes: [u8; 16] = [0u8; 16];
-            encrypter_bg
-                .lock()
-                .expect("encrypter lock")
-                .get_salt_bytes(&mut salt_bytes)?;
+            let seg_id = self
+                .segments
+                .read()
+                .expect("segments lock")
+                .last()
+                .map_or(0, |newest| newest + 1);
+            let (encrypter, wrapped_key) = self.keyring.new_data_key()?;
+            let entries = flush_table
+                .iter()
+                .map(|(k, v)| self.flush_entry(&encrypter, k, v))
+                .collect::<Result<Vec<_>>>()?;
+            self.blobs.sync()?;
+            let buf = self.build_segment(&FileKey::Wrapped(wrapped_key), entries)?;
+            write_segment(&segment_path(&self.curr_dir, seg_id), &buf)?;
+            sync_dir(&self.curr_dir)?;
+
+            // Note: the segment becomes visible before the frozen table is dropped, so readers never miss it
+            self.segments.write().expect("segments lock").push(seg_id);
+            // Note: retiring the log first means a table that is gone from the queue is never flushed twice
+            self.archive.archive(&frozen.wal)?;
+            self.frozen.lock().expect("frozen lock").pop_front();
+            self.flushed.notify_all();
+            drop(writer);
+        }
+        Ok(())
+    }
+
+    // Merges every segment into one, dropping tombstones and expired values. Values are carried
+    // over still encoded and blobs stay where they are, except for the live ones in blob files that
+    // are mostly dead, which are moved so those files can go.
+    // Note: the result replaces the newest input, which already shadows the others, so removing
+    // the older inputs afterwards is safe in any order and a crash at any point loses nothing
+    pub fn compact(&self) -> Result<CompactReport> {
+        let _writer = self.segment_writer.lock().expect("segment writer lock");
+        let inputs = self.segments.read().expect("segments lock").clone();
+        let Some(&newest) = inputs.last() else {
+            return Ok(CompactReport::default());
+        };
+
+        let mut merged: BTreeMap<Vec<u8>, PlainEntry> = BTreeMap::new();
+        let seg_iter = SegmentIter::new(
+            inputs.clone(),
+            self.curr_dir.clone(),
+            self.keyring.clone(),
+            self.blobs.clone(),
+        );
+        for seg in seg_iter {
+            for entry in seg?.scan_plain()? {
+                merged.entry(entry.key.clone()).or_insert(entry);
+            }
+        }
+
+        let now = now_millis();
+        let total = merged.len();
+        let mut live: Vec<PlainEntry> = merged
+            .into_values()
+            .filter(|entry| !entry.tombstone)
+            .filter(|entry| entry.expires_at.is_none_or(|expires_at| expires_at > now))
+            .collect();
+        let mut report = CompactReport {
+            segments_in: inputs.len(),
+            entries_kept: live.len(),
+            entries_dropped: total - live.len(),
+            ..Default::default()
+        };
+        report.blobs_relocated = self.relocate_blobs(&mut live)?;
+
+        let tmp_path = self
+            .curr_dir
+            .join(format!("segment_{}.sstable.tmp", newest));
+        if !live.is_empty() {
+            let (encrypter, wrapped_key) = self.keyring.new_data_key()?;
+            let entries = live
+                .iter()
+                .map(|entry| self.reseal_plain(&encrypter, entry))
+                .collect::<Result<Vec<_>, _>>()?;
+            let buf = self.build_segment(&FileKey::Wrapped(wrapped_key), entries)?;
+            write_segment(&tmp_path, &buf)?;
+        } else if tmp_path.exists() {
+            // Note: a leftover would read as a merged segment still staged, see `recover_compaction`
+            fs::remove_file(&tmp_path)?;
+        }
+
+        let mut segments = self.segments.write().expect("segments lock");
+        let removed = match live.is_empty() {
+            true => &inputs[..],
+            false => &inputs[..inputs.len() - 1],
+        };
+        // Note: the merged segment has no tombstones, so the inputs it replaces must never be
+        // read next to it. The marker lets an open after a crash finish removing them.
+        write_compact_marker(&self.curr_dir, newest, removed)?;
+        if !live.is_empty() {
+            fs::rename(&tmp_path, segment_path(&self.curr_dir, newest))?;
+            sync_dir(&self.curr_dir)?;
+        }
+        for id in removed {
+            fs::remove_file(segment_path(&self.curr_dir, *id))?;
+        }
+        sync_dir(&self.curr_dir)?;
+        fs::remove_file(self.curr_dir.join(COMPACT_FILE))?;
+        sync_dir(&self.curr_dir)?;
+        segments.retain(|id| !removed.contains(id));
+        drop(segments);
+
+        // Every pointer left is in the new segment, blob files it does not point into are garbage
+        let referenced = blob_refs(&live)?
+            .into_iter()
+            .map(|blob| blob.file)
+            .collect::<HashSet<_>>();
+        let active = self.blobs.active_id();
+        for (id, size) in self.blobs.file_sizes()? {
+            if !referenced.contains(&id) && Some(id) != active {
+                self.blobs.remove(id)?;
+                report.blob_files_removed += 1;
+                report.blob_bytes_freed += size;
+            }
+        }
+        sync_dir(&self.curr_dir)?;
+        Ok(report)
+    }
+
+    // Moves the live blobs out of files that are less than half live, returning how many moved
+    fn relocate_blobs(&self, live: &mut [PlainEntry]) -> Result<usize> {
+        let mut live_bytes: HashMap<usize, u64> = HashMap::new();
+        for blob in blob_refs(live)? {
+            *live_bytes.entry(blob.file).or_default() += blob.record_len();
+        }
+        let active = self.blobs.active_id();
+        let sparse: HashSet<usize> = self
+            .blobs
+            .file_sizes()?
+            .into_iter()
+            .filter(|(id, size)| {
+                Some(*id) != active && live_bytes.get(id).is_some_and(|live| live * 2 < *size)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        self.move_blobs(live, &sparse)
+    }
+
+    // Moves the blobs of `entries` that are in one of `files` to the active file, returning how
+    // many moved
+    fn move_blobs(&self, entries: &mut [PlainEntry], files: &HashSet<usize>) -> Result<usize> {
+        if files.is_empty() {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        for entry in entries.iter_mut().filter(|entry| entry.blob) {
+            let blob = BlobRef::decode(&entry.plaintext)?;
+            if !files.contains(&blob.file) {
+                continue;
+            }
+            let value = self.blobs.read(&entry.key, blob)?;
+            entry.plaintext = self
+                .blobs
+                .append(&entry.key, &value, self.compression, self.padding)?
+                .encode();
+            moved += 1;
+        }
+        self.blobs.sync()?;
+        Ok(moved)
+    }
+
+    // Checks every segment and log while flushes and compactions wait, see `verify::verify_dir`
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _writer = self.segment_writer.lock().expect("segment writer lock");
+        let keyring = &self.keyring;
+        let mut report = VerifyReport::default();
+        for &id in self.segments.read().expect("segments lock").iter() {
+            report.files.push(verify_segment::<V>(
+                &segment_path(&self.curr_dir, id),
+                keyring,
+            ));
+        }
+        for id in list_wal_ids(&self.curr_dir)? {
+            report
+                .files
+                .push(verify_wal::<V>(&wal_path(&self.curr_dir, id), keyring));
+        }
+        Ok(report)
+    }
+
+    // Puts `new` in place of the secret `old` opens, in the same key slot, see `change_slots`
+    pub fn change_password(self: &Arc<Self>, old: &Secret, new: &Secret) -> Result<PasswordChange> {
+        let current = self.header()?;
+        let (header, slot) = current.with_password(old, new)?;
+        Ok(self.change_slots(current, header, slot))
+    }
+
+    // Adds a key slot for `new`, `secret` has to open one of the others, see `change_slots`
+    pub fn add_key_slot(self: &Arc<Self>, secret: &Secret, new: &Secret) -> Result<PasswordChange> {
+        let current = self.header()?;
+        let (header, slot) = current.with_slot_added(secret, new)?;
+        Ok(self.change_slots(current, header, slot))
+    }
+
+    // Note: no file has to change, every slot wraps the same master key
+    pub fn remove_key_slot(&self, secret: &Secret, slot: u8) -> Result<()> {
+        let _writer = self.segment_writer.lock().expect("segment writer lock");
+        self.header()?
+            .without_slot(secret, slot)?
+            .write(&self.curr_dir)
+    }
+
+    pub fn key_slots(&self) -> Result<Vec<(u8, SlotKind)>> {
+        Ok(self.header()?.slots())
+    }
+
+    // Writes `header` in place of `current` in the background, see `rekey`. Reads and writes go
+    // on meanwhile, flushes and compactions wait until the change is done.
+    fn change_slots(
+        self: &Arc<Self>,
+        current: StoreHeader,
+        header: StoreHeader,
+        slot: u8,
+    ) -> PasswordChange {
+        let progress = Arc::new(Mutex::new(RekeyProgress::default()));
+        let store = Arc::clone(self);
+        let bg_progress = progress.clone();
+        let handle = thread::spawn(move || {
+            let result = store.rekey(&current, &header, slot, &bg_progress);
+            // Note: drops the staged files, or finishes the switch if the header already moved
+            if result.is_err()
+                && let Err(err) = rekey::recover(&store.curr_dir)
+            {
+                eprintln!("{err}")
+            }
+            result
+        });
+        PasswordChange::new(progress, handle)
+    }
+
+    // Files from before data keys only open under the password they were sealed with, so they
+    // are sealed again under data keys of their own before another secret can open the store.
+    // Blobs in such files move to the active one, segments pointing at them are written again.
+    fn rekey(
+        &self,
+        current: &StoreHeader,
+        header: &StoreHeader,
+        slot: u8,
+        progress: &Mutex<RekeyProgress>,
+    ) -> Result<RekeyReport> {
+        // Note: flushing first leaves fewer logs to seal again while writers wait
+        self.flush()?;
+        let _writer = self.segment_writer.lock().expect("segment writer lock");
+        if self.header()? != *current {
+            return Err(Error::msg(
+                "KvStore: key slots changed while waiting to change them",
+            ));
+        }
+        rekey::begin(&self.curr_dir, header)?;
+
+        let segments = self.segments.read().expect("segments lock").clone();
+        let archived = self.archive.list()?;
+        progress.lock().expect("rekey progress lock").files_total = segments.len() + archived.len();
+
+        let mut report = RekeyReport {
+            slot,
+            ..Default::default()
+        };
+        let mut legacy_blob_ids = HashSet::new();
+        for id in list_blob_ids(&self.curr_dir)? {
+            if self.blobs.file_key(id)?.is_none() {
+                legacy_blob_ids.insert(id);
+            }
+        }
+
+        let seg_iter = SegmentIter::new(
+            segments.clone(),
+            self.curr_dir.clone(),
+            self.keyring.clone(),
+            self.blobs.clone(),
+        );
+        // Note: the iterator goes newest first
+        for (&id, seg) in segments.iter().rev().zip(seg_iter) {
+            let path = segment_path(&self.curr_dir, id);
+            let footer = SegmentFile::parse_footer(&mut File::open(&path)?)?;
+            let wrapped = matches!(footer.key, FileKey::Wrapped(_));
+            if wrapped && legacy_blob_ids.is_empty() {
+                rekey::keep(progress);
+                continue;
+            }
+            let mut plains = seg?.scan_plain()?;
+            if self.move_blobs(&mut plains, &legacy_blob_ids)? == 0 && wrapped {
+                rekey::keep(progress);
+                continue;
+            }
+            let (encrypter, wrapped_key) = self.keyring.new_data_key()?;
+            let entries = plains
+                .iter()
+                .map(|entry| self.reseal_plain(&encrypter, entry))
+                .collect::<Result<Vec<_>, _>>()?;
+            let buf = self.build_segment(&FileKey::Wrapped(wrapped_key), entries)?;
+            rekey::stage(&path, &buf, progress)?;
+            report.segments += 1;
+        }
+        for archived in archived {
+            report.logs += self.rekey_log(&archived.path, progress)? as usize;
+        }
+
+        // Switchover: writers and segment readers wait while the live logs are staged
+        let _wal = self.wal.write().expect("wal lock");
+        let _segments = self.segments.write().expect("segments lock");
+        let live_logs = list_wal_ids(&self.curr_dir)?;
+        progress.lock().expect("rekey progress lock").files_total += live_logs.len();
+        for id in live_logs {
+            report.logs += self.rekey_log(&wal_path(&self.curr_dir, id), progress)? as usize;
+        }
+        rekey::commit(&self.curr_dir)?;
+
+        // Note: nothing points into these any more, a crash before they are gone leaves them to
+        // the next compaction
+        for id in legacy_blob_ids {
+            self.blobs.remove(id)?;
+            report.blob_files += 1;
+        }
+        sync_dir(&self.curr_dir)?;
+
+        report.bytes_written = progress.lock().expect("rekey progress lock").bytes_written;
+        Ok(report)
+    }
+
+    // Stages the log at `path` sealed again under a data key of its own if it is from before
+    // data keys, returning whether it was
+    fn rekey_log(&self, path: &Path, progress: &Mutex<RekeyProgress>) -> Result<bool> {
+        let recovery = read_records(&fs::read(path)?)?;
+        if recovery.key.is_some() {
+            rekey::keep(progress);
+            return Ok(false);
+        }
+        let buf = self.reseal_log(recovery)?;
+        rekey::stage(path, &buf, progress)?;
+        Ok(true)
+    }
 
-            footer[16..].copy_from_slice(&salt_bytes);
+    fn reseal_log(&self, recovery: Recovery) -> Result<Vec<u8>> {
+        let (encrypter, wrapped_key) = self.keyring.new_data_key()?;
+        let mut records = Vec::new();
+        for mut record in recovery.records {
+            let decrypter = record.decrypter(&self.keyring, None)?;
+            let Ok((key, plaintext)) = record.open(&decrypter) else {
+                continue;
+            };
+            let entry =
+                self.seal_entry(&encrypter, &key, record.expires_at, false, false, plaintext)?;
+            records.push(log_record(record.kind, record.timestamp, entry));
+        }
+        Ok(encode_log(&wrapped_key, &records))
+    }
 
-            buf.extend(&idx);
-            buf.extend_from_slice(&footer);
+    fn header(&self) -> Result<StoreHeader> {
+        StoreHeader::read(&self.curr_dir)?.ok_or_else(|| Error::msg("KvStore: no store header"))
+    }
 
-            seq_handle.write_all(buf.as_slice())?;
-            *seq_num = seq_num.add(1);
+    // Note: sorts by the key field, so entries with sealed keys end up in tag order
+    fn build_segment(&self, key: &FileKey, mut entries: Vec<RawEntry>) -> Result<Vec<u8>> {
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut builder = SegmentBuilder::new(entries.len());
+        for entry in &entries {
+            builder.add(entry);
+        }
+        Ok(builder.finish(key, self.compression))
+    }
 
-            rotate_log_file(
-                &&log_handle_bg,
-                &self.curr_dir.join("wal.log").as_path(),
-                &self.curr_dir.join("archive").as_path(),
-            )?
+    // Inline values come out of a segment decompressed and are compressed again with the current
+    // codec, blob pointers carry their own
+    fn reseal_plain(
+        &self,
+        encrypter: &DefaultEncrypter,
+        entry: &PlainEntry,
+    ) -> Result<RawEntry, KvError> {
+        let plaintext = match entry.blob || entry.tombstone {
+            true => entry.plaintext.clone(),
+            false => self.compression.compress(&entry.plaintext),
+        };
+        self.seal_entry(
+            encrypter,
+            &entry.key,
+            entry.expires_at,
+            entry.blob,
+            entry.tombstone,
+            plaintext,
+        )
+    }
 
-            // Note: Merge segments...
+    // Large values go to a blob file here, the WAL always holds them inline
+    fn flush_entry(
+        &self,
+        encrypter: &DefaultEncrypter,
+        key: &[u8],
+        value: &StoredValue<V>,
+    ) -> Result<RawEntry> {
+        let Some(inner) = &value.value else {
+            return Ok(self.seal_entry(
+                encrypter,
+                key,
+                value.expires_at,
+                false,
+                true,
+                Vec::new(),
+            )?);
+        };
+        let encoded = bincode::serde::encode_to_vec(inner, bincode::config::standard())?;
+        if encoded.len() <= self.blob_threshold {
+            let compressed = self.compression.compress(&encoded);
+            return Ok(self.seal_entry(
+                encrypter,
+                key,
+                value.expires_at,
+                false,
+                false,
+                compressed,
+            )?);
         }
+        let blob = self
+            .blobs
+            .append(key, &encoded, self.compression, self.padding)?;
+        Ok(self.seal_entry(encrypter, key, value.expires_at, true, false, blob.encode())?)
+    }
+
+    pub fn archive(&self) -> &WalArchive {
+        &self.archive
+    }
+
+    pub fn sync_wal(&self) -> Result<()> {
+        let active_wal = self.wal.read().expect("wal lock").clone();
+
+        // Older logs belong to memtables that were frozen but never made it into a segment
+        for id in list_wal_ids(&self.curr_dir)? {
+            if id >= active_wal.id() {
+                continue;
+            }
+            let wal = Wal::open(&self.curr_dir, id, self.durability, &self.keyring)?;
+            let table = self.replay_wal(&wal)?;
+            let frozen = Arc::new(FrozenMemtable { wal, table });
+            self.frozen
+                .lock()
+                .expect("frozen lock")
+                .push_back(frozen.clone());
+            self.flush_tx.send(frozen)?;
+        }
+
+        let table = self.replay_wal(&active_wal)?;
+        self.memtable.lock().expect("insert lock").extend(table);
         Ok(())
     }
 
-    pub fn sync_wal(&self, mut file: File) -> Result<()> {
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
-
-        for entry in buf.lines() {
-            let cmd_seq: Vec<_> = entry.split_whitespace().collect();
-            match cmd_seq[0] {
-                "SET" => {
-                    assert!(cmd_seq.len() == 5);
-                    let mut key: Vec<u8> = Vec::from(cmd_seq[1].as_bytes());
-                    let mut enc_string: Vec<u8> = BASE64_STANDARD.decode(cmd_seq[2])?;
-                    let nonce: Vec<u8> = BASE64_STANDARD.decode(cmd_seq[3])?;
-                    let salt_bytes: Vec<u8> = BASE64_STANDARD.decode(cmd_seq[4])?;
-
-                    let enc_bytes = enc_string.as_mut_slice();
-                    let nonce_bytes: [u8; 12] = nonce.try_into().expect("invalid nonce"); // worthy panic
-                    let salt = DefaultDecrypter::encode_salt_string(salt_bytes.as_slice())?;
-
-                    let log_decrypter = DefaultDecrypter::new(self.password.clone(), salt)?;
-
-                    if let Ok(plaintext_bytes) =
-                        log_decrypter.decrypt(enc_bytes, nonce_bytes, &mut key)
-                    {
-                        let (plain, _) = bincode::decode_from_slice(
-                            plaintext_bytes,
-                            bincode::config::standard(),
-                        )?;
-                        self.memtable
-                            .lock()
-                            .expect("insert lock")
-                            .insert(cmd_seq[1].to_string(), plain);
+    // The text log of a store from before binary WALs is written again through the active log,
+    // then moved to `archive/wal.log` where restores do not look. Until then it is replayed on
+    // every open, which only repeats the same writes.
+    fn replay_legacy_log(&self) -> Result<()> {
+        let path = self.curr_dir.join(LEGACY_LOG);
+        if !path.exists() {
+            return Ok(());
+        }
+        let recovery = read_legacy_log(&fs::read(&path)?);
+        if recovery.dropped_bytes > 0 {
+            eprintln!(
+                "wal: dropped {} bytes of torn or corrupt tail from {}",
+                recovery.dropped_bytes,
+                path.display()
+            );
+        }
+        for record in recovery.records {
+            let decrypter = record.decrypter(&self.keyring, None)?;
+            if let Some((key, value)) = self.decrypt_record(&decrypter, record)? {
+                self.write(key, value)?;
+            }
+        }
+        self.wal.read().expect("wal lock").sync()?;
+
+        let archive_dir = self.archive.dir();
+        fs::create_dir_all(archive_dir)?;
+        fs::rename(&path, archive_dir.join(LEGACY_LOG))?;
+        sync_dir(archive_dir)?;
+        sync_dir(&self.curr_dir)
+    }
+
+    fn replay_wal(&self, wal: &Wal) -> Result<Memtable<V>> {
+        let recovery = wal.recover()?;
+        if recovery.dropped_bytes > 0 {
+            eprintln!(
+                "wal: dropped {} bytes of torn or corrupt tail from {}",
+                recovery.dropped_bytes,
+                wal.path().display()
+            );
+        }
+
+        let mut table = BTreeMap::new();
+        let keyring = &self.keyring;
+        let log_decrypter = recovery.decrypter(keyring)?;
+        for record in recovery.records {
+            let decrypter = record.decrypter(keyring, log_decrypter.as_ref())?;
+            if let Some((key, value)) = self.decrypt_record(&decrypter, record)? {
+                table.insert(key, value);
+            }
+        }
+        Ok(table)
+    }
+
+    // Records that do not authenticate under this store's key are skipped
+    fn decrypt_record(
+        &self,
+        decrypter: &DefaultDecrypter,
+        mut record: WalRecord,
+    ) -> Result<Option<(Vec<u8>, StoredValue<V>)>> {
+        let Ok((key, plaintext)) = record.open(decrypter) else {
+            return Ok(None);
+        };
+        let value = match record.kind {
+            RecordKind::Set => {
+                let (plain, _) =
+                    bincode::serde::decode_from_slice(&plaintext, bincode::config::standard())?;
+                Some(plain)
+            }
+            RecordKind::Delete => None,
+        };
+        Ok(Some((
+            key,
+            StoredValue {
+                value,
+                expires_at: record.expires_at,
+            },
+        )))
+    }
+
+    // Copies the flushed segments to `dest` and marks which logs a restore has to replay on top
+    pub fn checkpoint(&self, dest: &Path) -> Result<()> {
+        let active_id = self.wal.read().expect("wal lock").id();
+        let _writer = self.segment_writer.lock().expect("segment writer lock");
+        let first_unflushed = self
+            .frozen
+            .lock()
+            .expect("frozen lock")
+            .front()
+            .map_or(active_id, |frozen| frozen.wal.id().min(active_id));
+
+        fs::create_dir_all(dest)?;
+        fs::copy(self.curr_dir.join(HEADER_FILE), dest.join(HEADER_FILE))?;
+        for seg in self.segments.read().expect("segments lock").iter() {
+            let file_name = format!("segment_{}.sstable", seg);
+            fs::copy(self.curr_dir.join(&file_name), dest.join(&file_name))?;
+        }
+        write_checkpoint(dest, first_unflushed)?;
+        sync_dir(dest)
+    }
+
+    // Replays the logs of the store at `source` into this one, which has to be empty or a checkpoint
+    pub fn restore_to_point(&self, source: &Path, target: RecoveryTarget) -> Result<RestoreReport> {
+        if fs::canonicalize(source)? == fs::canonicalize(&self.curr_dir)? {
+            return Err(Error::msg("restore: source and target are the same store"));
+        }
+
+        let first_id = read_checkpoint(&self.curr_dir)?.unwrap_or(0);
+        let mut report = RestoreReport::default();
+        // Note: the source logs have their data keys wrapped under the source's master key, an
+        // empty target got a master key of its own
+        let keyring = &header::keyring(source, self.keyring.secret())?;
+
+        'logs: for (id, path) in logs_since(source, first_id)? {
+            if !target.includes_log(id) {
+                break;
+            }
+            let recovery = read_records(&fs::read(&path)?)?;
+            report.logs += 1;
+            report.dropped_bytes += recovery.dropped_bytes;
+            let log_decrypter = recovery
+                .decrypter(keyring)
+                .map_err(|err| err.context(format!("restore: {} does not open", path.display())))?;
+
+            for record in recovery.records {
+                if !target.includes_record(record.timestamp) {
+                    break 'logs;
+                }
+                let timestamp = record.timestamp;
+                let decrypter = record.decrypter(keyring, log_decrypter.as_ref())?;
+                match self.decrypt_record(&decrypter, record)? {
+                    Some((key, value)) => {
+                        self.write(key, value)?;
+                        report.records += 1;
+                        report.last_timestamp = Some(timestamp);
                     }
+                    None => report.skipped += 1,
                 }
-                _ => return Err(Error::msg("unknown cmd")),
-            };
+            }
         }
-        Ok(())
+        Ok(report)
     }
 
-    pub fn write_wal(&self, k: String, v: V) -> Result<()>
-    where
-        V: bincode::Encode,
-    {
-        let (mut sealed_bytes, mut nonce) = self.build_entry(&k, &v)?;
+    fn write(&self, key: Vec<u8>, value: StoredValue<V>) -> Result<()> {
+        let active_wal = self.wal.read().expect("wal lock");
+        self.write_wal(&active_wal, &key, &value)?;
 
-        let encrypter = self.encypter_guard.lock().expect("unable to acquire lock");
-        let mut salt_bytes: [u8; 16] = [0u8; 16];
-        encrypter.get_salt_bytes(&mut salt_bytes)?;
+        let mut memtable = self.memtable.lock().expect("insert lock");
+        memtable.insert(key, value);
+        let full = memtable.len() >= MAX_MEMTABLE;
+        drop(memtable);
+        drop(active_wal);
 
-        let encoded_string = BASE64_STANDARD.encode(&mut sealed_bytes);
-        let nonce = BASE64_STANDARD.encode(&mut nonce);
-        let salt_encoded = BASE64_STANDARD.encode(&mut salt_bytes);
+        if full {
+            self.freeze_memtable(false)?;
+        }
+        Ok(())
+    }
 
-        let log_entry = format!("SET {} {} {} {}\n", k, encoded_string, nonce, salt_encoded);
-        let mut buf = Vec::from(log_entry.as_bytes());
+    // Swaps in an empty memtable with a fresh WAL and queues the old one for flushing
+    fn freeze_memtable(&self, force: bool) -> Result<()> {
+        let mut active_wal = self.wal.write().expect("wal lock");
+        let mut memtable = self.memtable.lock().expect("get mut");
+        if memtable.is_empty() || (!force && memtable.len() < MAX_MEMTABLE) {
+            return Ok(());
+        }
 
-        self.log_handle
+        let next_wal = Wal::open(
+            &self.curr_dir,
+            active_wal.id() + 1,
+            self.durability,
+            &self.keyring,
+        )?;
+        let frozen = Arc::new(FrozenMemtable {
+            wal: std::mem::replace(&mut *active_wal, next_wal),
+            table: std::mem::take(&mut *memtable),
+        });
+        self.frozen
             .lock()
-            .expect("unable to lock file")
-            .write_all(&mut buf)?;
+            .expect("frozen lock")
+            .push_back(frozen.clone());
+        self.flush_tx.send(frozen)?;
         Ok(())
     }
 
-    pub fn insert_parsed(&self, key: String, value: String) -> Result<(), KvError> {
-        if let Ok(value) = value.parse::<V>() {
-            self.memtable
-                .lock()
-                .expect("insert lock")
-                .insert(key, value);
+    fn find_in_frozen(&self, key: &[u8]) -> Option<StoredValue<V>> {
+        let frozen = self.frozen.lock().expect("frozen lock");
+        frozen
+            .iter()
+            .rev()
+            .find_map(|table| table.table.get(key).cloned())
+    }
+
+    pub fn write_wal(&self, wal: &Wal, k: &[u8], v: &StoredValue<V>) -> Result<()> {
+        let entry = self.build_entry(wal.encrypter()?, k, v)?;
+
+        let kind = if v.value.is_some() {
+            RecordKind::Set
         } else {
-            return Err(KvError("insert"));
+            RecordKind::Delete
+        };
+        wal.append(&log_record(kind, now_millis(), entry))
+    }
+
+    // Note: tombstones seal an empty plaintext, so they still authenticate against the key, and
+    // the record kind tells them apart from values that encode to nothing
+    fn build_entry(
+        &self,
+        encrypter: &DefaultEncrypter,
+        key: &[u8],
+        value: &StoredValue<V>,
+    ) -> Result<RawEntry, KvError> {
+        let plaintext = match &value.value {
+            Some(value) => bincode::serde::encode_to_vec(value, bincode::config::standard())?,
+            None => Vec::new(),
+        };
+        self.seal_entry(encrypter, key, value.expires_at, false, false, plaintext)
+    }
+
+    fn seal_entry(
+        &self,
+        encrypter: &DefaultEncrypter,
+        key: &[u8],
+        expires_at: Option<u64>,
+        blob: bool,
+        tombstone: bool,
+        plaintext: Vec<u8>,
+    ) -> Result<RawEntry, KvError> {
+        let mut entry = RawEntry {
+            key: key.to_vec(),
+            expires_at,
+            blob,
+            sealed_key: false,
+            padded: false,
+            tombstone: Some(tombstone),
+            nonce: [0; 12],
+            sealed: plaintext,
+        };
+        if self.encrypt_keys {
+            entry.key = encrypter.key_tag(key);
+            entry.sealed = wrap_key(key, &entry.sealed);
+            entry.sealed_key = true;
         }
-        Ok(())
+        entry.padded = self.padding.pad(&mut entry.sealed)?;
+        let aad = entry.aad();
+        entry.nonce = encrypter.encrypt(&mut entry.sealed, Some(&aad))?;
+        Ok(entry)
     }
+}
 
-    fn build_entry(&self, key: &str, value: &V) -> Result<(Vec<u8>, [u8; 12]), KvError> {
-        let encrypter = self
-            .encypter_guard
-            .lock()
-            .expect("unable to acquire a lock");
-        let mut sealed_bytes = bincode::encode_to_vec(value, bincode::config::standard())?;
-        let nonce = encrypter.encrypt(&mut sealed_bytes, Some(key.as_bytes()))?;
-        Ok((sealed_bytes, nonce))
+// Encrypted keys and padding stay on once a run turned them on, a run without the options would
+// write keys and value lengths in the clear again. A padding policy given replaces the recorded one.
+fn keep_settings(dir: &Path, options: &mut Options) -> Result<()> {
+    let header = StoreHeader::read(dir)?.ok_or_else(|| Error::msg("KvStore: no store header"))?;
+    let recorded = header.settings();
+    let settings = StoreSettings {
+        encrypt_keys: recorded.encrypt_keys || options.encrypt_keys,
+        padding: match options.padding {
+            Padding::None => recorded.padding,
+            padding => padding,
+        },
+    };
+    if settings != recorded {
+        header
+            .with_settings(&options.secret, settings)?
+            .write(dir)?;
     }
+    options.encrypt_keys = settings.encrypt_keys;
+    options.padding = settings.padding;
+    Ok(())
 }
 
-fn rotate_log_file(
-    log_handle: &Arc<Mutex<File>>,
-    log_path: &Path,
-    archive_dir: &Path,
-) -> Result<()> {
-    fs::create_dir_all(archive_dir)?;
+fn log_record(kind: RecordKind, timestamp: u64, entry: RawEntry) -> WalRecord {
+    WalRecord {
+        kind,
+        timestamp,
+        expires_at: entry.expires_at,
+        key: entry.key,
+        sealed_key: entry.sealed_key,
+        padded: entry.padded,
+        nonce: entry.nonce,
+        salt: [0; 16],
+        sealed: entry.sealed,
+    }
+}
 
-    let mut log_guard = log_handle.lock().expect("lock log file handle");
-    let archive_path = archive_dir.join("wal_log");
+fn blob_refs(entries: &[PlainEntry]) -> Result<Vec<BlobRef>> {
+    entries
+        .iter()
+        .filter(|entry| entry.blob)
+        .map(|entry| BlobRef::decode(&entry.plaintext))
+        .collect()
+}
 
-    if log_path.exists() {
-        fs::rename(log_path, &archive_path)?;
+// Ids of the `segment_<id>.sstable` files in `dir`, oldest first
+pub fn list_segment_ids(dir: &Path) -> Result<Vec<usize>> {
+    let mut ids: Vec<usize> = fs::read_dir(dir)?
+        .filter_map(|entry_result| {
+            let entry = entry_result.ok()?;
+            let file_name = entry.file_name();
+            file_name
+                .to_str()?
+                .strip_prefix("segment_")?
+                .strip_suffix(".sstable")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+pub fn segment_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("segment_{}.sstable", id))
+}
+
+// `<newest>\n<removed ids>\n` of a compaction under way, see `recover_compaction`
+pub const COMPACT_FILE: &str = "COMPACT";
+
+fn write_compact_marker(dir: &Path, newest: usize, removed: &[usize]) -> Result<()> {
+    let ids: Vec<String> = removed.iter().map(ToString::to_string).collect();
+    let tmp_path = dir.join(format!("{COMPACT_FILE}.tmp"));
+    write_segment(
+        &tmp_path,
+        format!("{newest}\n{}\n", ids.join(" ")).as_bytes(),
+    )?;
+    fs::rename(tmp_path, dir.join(COMPACT_FILE))?;
+    sync_dir(dir)
+}
+
+// Settles a compaction cut short. While the merged segment is still staged the inputs are all
+// there and it is dropped, once it is in place the inputs it replaces are removed.
+pub fn recover_compaction(dir: &Path) -> Result<()> {
+    let path = dir.join(COMPACT_FILE);
+    if !path.exists() {
+        return Ok(());
     }
-    let new_log_file = OpenOptions::new()
+    let marker = fs::read_to_string(&path)?;
+    let mut lines = marker.lines();
+    let newest: usize = lines
+        .next()
+        .ok_or_else(|| Error::msg("compact: empty marker"))?
+        .parse()?;
+    let removed = lines
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<usize>, _>>()?;
+
+    let staged = dir.join(format!("segment_{newest}.sstable.tmp"));
+    if staged.exists() {
+        fs::remove_file(staged)?;
+    } else {
+        for id in removed {
+            match fs::remove_file(segment_path(dir, id)) {
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+    }
+    sync_dir(dir)?;
+    fs::remove_file(path)?;
+    sync_dir(dir)
+}
+
+pub fn write_segment(path: &Path, buf: &[u8]) -> Result<()> {
+    let mut seg_handle = OpenOptions::new()
         .write(true)
         .create(true)
-        .append(true)
-        .open(log_path)?;
+        .truncate(true)
+        .open(path)?;
+    seg_handle.write_all(buf)?;
+    seg_handle.sync_all()?;
+    Ok(())
+}
 
-    *log_guard = new_log_file;
+// The password does not match the store header, nothing was read or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongPassword;
 
-    Ok(())
+impl Display for WrongPassword {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "KvStore: wrong password for this store")
+    }
 }
 
+impl std::error::Error for WrongPassword {}
+
+// The deadline a TTL gives is past what a timestamp holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidExpireTime;
+
+impl Display for InvalidExpireTime {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid expire time")
+    }
+}
+
+impl std::error::Error for InvalidExpireTime {}
+
 #[derive(Debug, Clone)]
 pub struct KvError(pub &'static str);
 
@@ -315,12 +1269,6 @@ impl From<std::io::Error> for KvError {
     }
 }
 
-impl<V> From<SendError<BTreeMap<String, V>>> for KvError {
-    fn from(_: SendError<BTreeMap<String, V>>) -> Self {
-        KvError("placeholder")
-    }
-}
-
 impl From<EncryptError> for KvError {
     fn from(_value: EncryptError) -> Self {
         KvError("Failed to encrypt")
@@ -344,3 +1292,243 @@ impl From<bincode::error::DecodeError> for KvError {
         KvError("Failed to decode")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::testutil;
+
+    fn open(dir: &Path) -> Arc<KvStore<Vec<u8>>> {
+        KvStore::open(dir, Options::with_secret(Secret::Key([1; 32]))).expect("open store")
+    }
+
+    fn footer_key(dir: &Path, id: usize) -> FileKey {
+        let mut seg_file = File::open(segment_path(dir, id)).unwrap();
+        SegmentFile::parse_footer(&mut seg_file).unwrap().key
+    }
+
+    // A segment as stores wrote it before data keys, sealed under the key the secret derives
+    fn write_legacy_segment(store: &KvStore<Vec<u8>>, dir: &Path, entries: &[(&[u8], &[u8])]) {
+        let encrypter = DefaultEncrypter::from_key(&[1; 32]).unwrap();
+        let entries = entries
+            .iter()
+            .map(|(key, value)| {
+                let value = StoredValue {
+                    value: Some(value.to_vec()),
+                    expires_at: None,
+                };
+                store.flush_entry(&encrypter, key, &value).unwrap()
+            })
+            .collect();
+        let buf = store
+            .build_segment(&FileKey::Salt([7; 16]), entries)
+            .unwrap();
+        let id = list_segment_ids(dir).unwrap().last().map_or(0, |id| id + 1);
+        write_segment(&segment_path(dir, id), &buf).unwrap();
+    }
+
+    #[test]
+    fn every_file_gets_its_own_data_key() {
+        let dir = testutil::temp_dir("store");
+        let store = open(&dir);
+        for n in 0..3u8 {
+            store.put(&[n], vec![n]).unwrap();
+            store.put(b"blob", vec![n; BLOB_THRESHOLD + 1]).unwrap();
+            store.flush().unwrap();
+        }
+        store.put(b"logged", b"v".to_vec()).unwrap();
+
+        let mut keys: Vec<FileKey> = list_segment_ids(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|id| footer_key(&dir, id))
+            .collect();
+        assert_eq!(keys.len(), 3);
+        for (_, path) in logs_since(&dir, 0).unwrap() {
+            keys.push(read_records(&fs::read(path).unwrap()).unwrap().key.unwrap());
+        }
+        for (id, _) in store.blobs.file_sizes().unwrap() {
+            keys.push(FileKey::Wrapped(store.blobs.file_key(id).unwrap().unwrap()));
+        }
+
+        assert!(keys.iter().all(|key| matches!(key, FileKey::Wrapped(_))));
+        let distinct: HashSet<_> = keys
+            .iter()
+            .map(|key| match key {
+                FileKey::Wrapped(wrapped) => wrapped.to_vec(),
+                FileKey::Salt(salt) => salt.to_vec(),
+            })
+            .collect();
+        assert!(keys.len() > 4);
+        assert_eq!(distinct.len(), keys.len());
+        store.flush().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_from_before_data_keys_are_sealed_again_once() {
+        let dir = testutil::temp_dir("store");
+        let store = open(&dir);
+        store.put(b"new", b"1".to_vec()).unwrap();
+        store.flush().unwrap();
+        write_legacy_segment(&store, &dir, &[(b"a", b"old a"), (b"b", b"old b")]);
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"old a".to_vec()));
+        let legacy_id = *list_segment_ids(&dir).unwrap().last().unwrap();
+        assert_eq!(footer_key(&dir, legacy_id), FileKey::Salt([7; 16]));
+
+        let new = Secret::Key([3; 32]);
+        let change = store.change_password(&Secret::Key([1; 32]), &new).unwrap();
+        assert_eq!(change.wait().unwrap().segments, 1);
+        assert!(matches!(footer_key(&dir, legacy_id), FileKey::Wrapped(_)));
+        let change = store.change_password(&new, &new).unwrap();
+        assert_eq!(change.wait().unwrap().segments, 0);
+        drop(store);
+
+        let store = KvStore::<Vec<u8>>::open(&dir, Options::with_secret(new)).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"old a".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"old b".to_vec()));
+        assert_eq!(store.get(b"new").unwrap(), Some(b"1".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Every file under `dir` that holds `needle` as is
+    fn files_holding(dir: &Path, needle: &[u8]) -> Vec<PathBuf> {
+        testutil::snapshot(dir)
+            .into_iter()
+            .filter(|(_, bytes)| bytes.windows(needle.len()).any(|window| window == needle))
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    #[test]
+    fn encrypted_keys_never_reach_the_disk_in_the_clear() {
+        let dir = testutil::temp_dir("store");
+        let mut options = Options::with_secret(Secret::Key([1; 32]));
+        options.encrypt_keys = true;
+        let store = KvStore::<Vec<u8>>::open(&dir, options).unwrap();
+        for n in 0..10u8 {
+            store
+                .put(format!("needle-{n}").as_bytes(), vec![n])
+                .unwrap();
+        }
+        store
+            .put(b"needle-blob", vec![1; BLOB_THRESHOLD + 1])
+            .unwrap();
+        store
+            .put_with_ttl(b"needle-ttl", b"v".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        store.delete(b"needle-0").unwrap();
+        store.flush().unwrap();
+        store.compact().unwrap();
+        store.put(b"needle-logged", b"v".to_vec()).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        assert_eq!(files_holding(&dir, b"needle"), Vec::<PathBuf>::new());
+
+        // A later run without the flag keeps to what the header records
+        let store = open(&dir);
+        assert!(
+            StoreHeader::read(&dir)
+                .unwrap()
+                .unwrap()
+                .settings()
+                .encrypt_keys
+        );
+        store.put(b"needle-later", b"v".to_vec()).unwrap();
+        store.flush().unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get(b"needle-0").unwrap(), None);
+        assert_eq!(store.get(b"needle-1").unwrap(), Some(vec![1]));
+        assert_eq!(store.get(b"needle-later").unwrap(), Some(b"v".to_vec()));
+        let page = store.scan(None, 100).unwrap();
+        assert!(page.entries.iter().any(|(key, _)| key == b"needle-blob"));
+        drop(store);
+        assert_eq!(files_holding(&dir, b"needle"), Vec::<PathBuf>::new());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_password_leaves_the_directory_untouched() {
+        let dir = testutil::temp_dir("store");
+        let store = open(&dir);
+        store.put(b"k", b"v".to_vec()).unwrap();
+        store.flush().unwrap();
+        store.put(b"in the log", b"v".to_vec()).unwrap();
+        drop(store);
+
+        // A password change and a compaction cut short, which a right password would settle
+        let ids = list_segment_ids(&dir).unwrap();
+        let segment = segment_path(&dir, ids[0]);
+        fs::copy(&segment, segment.with_extension("sstable.rekey")).unwrap();
+        fs::copy(dir.join(HEADER_FILE), dir.join("STORE.rekey")).unwrap();
+        write_compact_marker(&dir, ids[0], &[]).unwrap();
+        let before = testutil::snapshot(&dir);
+
+        let wrong = Options::with_secret(Secret::Key([2; 32]));
+        let Err(err) = KvStore::<Vec<u8>>::open(&dir, wrong) else {
+            panic!("opened with a wrong password");
+        };
+        assert!(err.downcast_ref::<WrongPassword>().is_some(), "{err}");
+        let err = crate::repair::repair_dir::<Vec<u8>>(&dir, &Secret::Key([2; 32])).unwrap_err();
+        assert!(err.downcast_ref::<WrongPassword>().is_some(), "{err}");
+        assert!(testutil::snapshot(&dir) == before, "the directory changed");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crash_before_compacted_inputs_are_removed_keeps_deletes() {
+        let dir = testutil::temp_dir("store");
+        let store = open(&dir);
+        store.put(b"a", b"1".to_vec()).unwrap();
+        store.put(b"k", b"secret".to_vec()).unwrap();
+        store.flush().unwrap();
+        store.delete(b"k").unwrap();
+        store.put(b"b", b"2".to_vec()).unwrap();
+        store.flush().unwrap();
+
+        let ids = list_segment_ids(&dir).unwrap();
+        assert_eq!(ids.len(), 2);
+        let oldest = fs::read(segment_path(&dir, ids[0])).unwrap();
+        store.compact().unwrap();
+        drop(store);
+
+        // The merged segment is in place but the input it replaced, and the marker, are not gone
+        fs::write(segment_path(&dir, ids[0]), oldest).unwrap();
+        write_compact_marker(&dir, ids[1], &ids[..1]).unwrap();
+
+        let store = open(&dir);
+        assert_eq!(store.get(b"k").unwrap(), None);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(list_segment_ids(&dir).unwrap(), vec![ids[1]]);
+        assert!(!dir.join(COMPACT_FILE).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crash_before_merged_segment_is_renamed_keeps_inputs() {
+        let dir = testutil::temp_dir("store");
+        let store = open(&dir);
+        store.put(b"k", b"secret".to_vec()).unwrap();
+        store.flush().unwrap();
+        store.delete(b"k").unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let ids = list_segment_ids(&dir).unwrap();
+        let staged = dir.join(format!("segment_{}.sstable.tmp", ids[1]));
+        fs::write(&staged, b"half written").unwrap();
+        write_compact_marker(&dir, ids[1], &ids[..1]).unwrap();
+
+        let store = open(&dir);
+        assert_eq!(store.get(b"k").unwrap(), None);
+        assert_eq!(list_segment_ids(&dir).unwrap(), ids);
+        assert!(!staged.exists());
+        assert!(!dir.join(COMPACT_FILE).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A fresh directory under the system temp dir, unique per process and call
+pub fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "enc-kv-{name}-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).expect("create temp dir");
+    dir
+}
+
+// Every file under `dir` with its contents
+pub fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(dir).expect("read dir") {
+        let path = entry.expect("dir entry").path();
+        match path.is_dir() {
+            true => files.extend(snapshot(&path)),
+            false => {
+                let bytes = fs::read(&path).expect("read file");
+                files.insert(path, bytes);
+            }
+        }
+    }
+    files
+}
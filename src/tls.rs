@@ -0,0 +1,254 @@
+use anyhow::{Result, anyhow};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    crypto::{CryptoProvider, ring},
+    server::WebPkiClientVerifier,
+    version::TLS13,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// Anything a connection handler can talk through, plain TCP or TLS
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+// PEM files for one side of a connection: the CA both sides trust, plus a certificate and key it signed
+// Note: the server only accepts clients presenting a certificate from the same CA
+#[derive(Debug, Clone)]
+pub struct TlsIdentity {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsIdentity {
+    // The `<dir>/ca.pem`, `<dir>/<name>.pem` and `<dir>/<name>.key` layout of `generate_test_certs`
+    pub fn in_dir(dir: &Path, name: &str) -> Self {
+        Self {
+            ca: dir.join("ca.pem"),
+            cert: dir.join(format!("{name}.pem")),
+            key: dir.join(format!("{name}.key")),
+        }
+    }
+
+    fn load(
+        &self,
+    ) -> Result<(
+        RootCertStore,
+        Vec<CertificateDer<'static>>,
+        PrivateKeyDer<'static>,
+    )> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&self.ca)? {
+            roots.add(cert?)?;
+        }
+        let certs = CertificateDer::pem_file_iter(&self.cert)?.collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate in {}", self.cert.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)?;
+        Ok((roots, certs, key))
+    }
+}
+
+// TLS 1.3 only, on the ring provider the rest of the crate already uses
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub fn server_config(identity: &TlsIdentity) -> Result<Arc<ServerConfig>> {
+    let (roots, certs, key) = identity.load()?;
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider()).build()?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&TLS13])?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(identity: &TlsIdentity) -> Result<Arc<ClientConfig>> {
+    let (roots, certs, key) = identity.load()?;
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&TLS13])?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+// A throwaway CA plus `server` and `client` identities in `dir`, for loopback setups and tests
+// `server_names` are the DNS names or IPs clients will connect with
+pub fn generate_test_certs(dir: &Path, server_names: &[String]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "enc-kv-store test CA");
+    ca_params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate()?)?;
+    fs::write(dir.join("ca.pem"), ca.pem())?;
+
+    for (name, alt_names, usage) in [
+        (
+            "server",
+            server_names.to_vec(),
+            ExtendedKeyUsagePurpose::ServerAuth,
+        ),
+        ("client", Vec::new(), ExtendedKeyUsagePurpose::ClientAuth),
+    ] {
+        let mut params = CertificateParams::new(alt_names)?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, format!("enc-kv-store {name}"));
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &ca)?;
+
+        let identity = TlsIdentity::in_dir(dir, name);
+        fs::write(&identity.cert, cert.pem())?;
+        write_private(&identity.key, key.serialize_pem().as_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::encryption::Secret;
+    use crate::proto::{Request, Response};
+    use crate::server;
+    use crate::store::{KvStore, Options};
+    use crate::testutil;
+    use rustls::{ClientConnection, StreamOwned};
+    use rustls_pki_types::ServerName;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    fn cert_dir() -> PathBuf {
+        let dir = testutil::temp_dir("tls");
+        generate_test_certs(&dir, &["localhost".to_string()]).expect("generate certs");
+        dir
+    }
+
+    // A store served over mutual TLS with the certificates in `dir`, natively and over RESP
+    fn serve(dir: &Path) -> (SocketAddr, SocketAddr) {
+        let store: Arc<KvStore<Vec<u8>>> = KvStore::open(
+            dir.join("store"),
+            Options::with_secret(Secret::Key([1; 32])),
+        )
+        .unwrap();
+        let config = server_config(&TlsIdentity::in_dir(dir, "server")).unwrap();
+        let native = TcpListener::bind("127.0.0.1:0").unwrap();
+        let resp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = (native.local_addr().unwrap(), resp.local_addr().unwrap());
+        let (native_store, native_config) = (store.clone(), config.clone());
+        thread::spawn(move || server::serve_native(native_store, native, Some(native_config)));
+        thread::spawn(move || server::serve(store, resp, Some(config)));
+        addrs
+    }
+
+    #[test]
+    fn native_client_round_trip_over_mutual_tls() {
+        let dir = cert_dir();
+        let (native, _) = serve(&dir);
+        let config = client_config(&TlsIdentity::in_dir(&dir, "client")).unwrap();
+        let client = Client::connect_tls(native, config, "localhost").unwrap();
+
+        client.put(b"k", b"v").unwrap();
+        assert_eq!(client.get(b"k").unwrap(), Some(b"v".to_vec()));
+        let mut pipeline = client.pipeline();
+        for n in 0..100u8 {
+            pipeline.push(Request::Put {
+                key: vec![n],
+                value: vec![n; 1024],
+                ttl: None,
+            });
+            pipeline.push(Request::Get { key: vec![n] });
+        }
+        let responses = pipeline.execute().unwrap();
+        for (n, pair) in responses.chunks(2).enumerate() {
+            assert!(matches!(pair[0], Response::Done));
+            assert!(matches!(&pair[1], Response::Value(Some(got)) if *got == vec![n as u8; 1024]));
+        }
+        client.flush().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resp_round_trip_over_mutual_tls() {
+        let dir = cert_dir();
+        let (_, resp) = serve(&dir);
+        let config = client_config(&TlsIdentity::in_dir(&dir, "client")).unwrap();
+        let conn = ClientConnection::new(config, ServerName::try_from("localhost").unwrap());
+        let mut stream = StreamOwned::new(conn.unwrap(), TcpStream::connect(resp).unwrap());
+
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        stream.flush().unwrap();
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn clients_without_a_trusted_certificate_are_rejected() {
+        let (dir, other) = (cert_dir(), cert_dir());
+        let (native, _) = serve(&dir);
+
+        // Note: trusts the server, but presents a certificate from another CA
+        let identity = TlsIdentity {
+            ca: dir.join("ca.pem"),
+            ..TlsIdentity::in_dir(&other, "client")
+        };
+        let config = client_config(&identity).unwrap();
+        let Err(err) = Client::connect_tls(native, config, "localhost") else {
+            panic!("connected with an untrusted certificate");
+        };
+        assert!(err.to_string().contains("fatal alert"), "{err}");
+
+        // A client of another CA does not trust the server either
+        let config = client_config(&TlsIdentity::in_dir(&other, "client")).unwrap();
+        let Err(err) = Client::connect_tls(native, config, "localhost") else {
+            panic!("connected to an untrusted server");
+        };
+        assert!(
+            err.to_string().contains("invalid peer certificate"),
+            "{err}"
+        );
+
+        // Nor is plain TCP answered
+        let plain = Client::connect(native).unwrap();
+        assert!(plain.ping().is_err());
+
+        let config = client_config(&TlsIdentity::in_dir(&dir, "client")).unwrap();
+        let client = Client::connect_tls(native, config, "localhost").unwrap();
+        assert!(client.ping().is_ok());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(other).unwrap();
+    }
+}
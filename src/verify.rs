@@ -0,0 +1,360 @@
+use crate::blob::BlobStore;
+use crate::encryption::Secret;
+use crate::header;
+use crate::keyring::Keyring;
+use crate::repl::display_bytes;
+use crate::segment::{self, SegmentFile};
+use crate::store::{list_segment_ids, segment_path};
+use crate::wal::{RecordKind, list_wal_ids, read_records, wal_path};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// Outcome for one segment or log, `entries` counts the entries that authenticated
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub entries: usize,
+    pub problems: Vec<String>,
+}
+
+impl FileReport {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            entries: 0,
+            problems: Vec::new(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(FileReport::is_ok)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.files.iter().filter(|file| !file.is_ok()).count()
+    }
+}
+
+// Checks every segment and log of a store directory without opening the store,
+// so nothing is replayed or truncated before it is looked at
+pub fn verify_dir<V>(dir: &Path, secret: &Secret) -> Result<VerifyReport>
+where
+    V: DeserializeOwned,
+{
+    let keyring = Arc::new(header::keyring(dir, secret)?);
+    let mut report = VerifyReport::default();
+    for id in list_segment_ids(dir)? {
+        report
+            .files
+            .push(verify_segment::<V>(&segment_path(dir, id), &keyring));
+    }
+    for id in list_wal_ids(dir)? {
+        report
+            .files
+            .push(verify_wal::<V>(&wal_path(dir, id), &keyring));
+    }
+    Ok(report)
+}
+
+// Footer and index layout, key order and every entry's authentication tag
+pub fn verify_segment<V>(path: &Path, keyring: &Arc<Keyring>) -> FileReport
+where
+    V: DeserializeOwned,
+{
+    let mut report = FileReport::new(path);
+    if let Err(err) = check_segment::<V>(path, keyring, &mut report) {
+        report.problems.push(err.to_string());
+    }
+    report
+}
+
+fn check_segment<V>(path: &Path, keyring: &Arc<Keyring>, report: &mut FileReport) -> Result<()>
+where
+    V: DeserializeOwned,
+{
+    let mut seg_file = File::open(path)?;
+    let footer = SegmentFile::parse_footer(&mut seg_file)?;
+    if footer.idx_offset.checked_add(footer.idx_size) != Some(footer.footer_offset) {
+        report.problems.push(format!(
+            "index at {} with {} bytes does not end at the footer at {}",
+            footer.idx_offset, footer.idx_size, footer.footer_offset
+        ));
+    }
+
+    let scan = segment::read_entries(&seg_file, &footer)?;
+    if let Some((offset, err)) = &scan.error {
+        report
+            .problems
+            .push(format!("entry at {offset} does not parse: {err}"));
+    }
+    for pair in scan.entries.windows(2) {
+        let ((_, prev), (offset, entry)) = (&pair[0], &pair[1]);
+        if prev.key >= entry.key {
+            report.problems.push(format!(
+                "entry {} at {offset} is not ordered after {}",
+                display_bytes(&entry.key),
+                display_bytes(&prev.key)
+            ));
+        }
+    }
+
+    match segment::read_index(&seg_file, &footer) {
+        Ok(index) => {
+            let starts: HashMap<u64, &[u8]> = scan
+                .entries
+                .iter()
+                .map(|(offset, entry)| (*offset, entry.key.as_slice()))
+                .collect();
+            for (key, offset) in &index {
+                match starts.get(offset) {
+                    Some(entry_key) if *entry_key == key.as_slice() => {}
+                    Some(entry_key) => report.problems.push(format!(
+                        "index key {} points at entry {} at {offset}",
+                        display_bytes(key),
+                        display_bytes(entry_key)
+                    )),
+                    None => report.problems.push(format!(
+                        "index key {} points at {offset}, which is not an entry",
+                        display_bytes(key)
+                    )),
+                }
+            }
+            for pair in index.windows(2) {
+                if pair[0].0 >= pair[1].0 {
+                    report.problems.push(format!(
+                        "index key {} is not ordered after {}",
+                        display_bytes(&pair[1].0),
+                        display_bytes(&pair[0].0)
+                    ));
+                }
+            }
+        }
+        Err(err) => report.problems.push(format!("index: {err}")),
+    }
+
+    let decrypter = keyring.decrypter(&footer.key)?;
+    let blobs = BlobStore::new(path.parent().unwrap_or(Path::new(".")), keyring.clone())?;
+    for (offset, entry) in scan.entries {
+        let key = display_bytes(&entry.key);
+        match segment::open_entry::<V>(&decrypter, footer.compression, &blobs, entry) {
+            Ok(_) => report.entries += 1,
+            Err(err) => report
+                .problems
+                .push(format!("entry {key} at {offset} does not open: {err}")),
+        }
+    }
+    Ok(())
+}
+
+// Frame checksums up to the end of the log and every record's authentication tag
+pub fn verify_wal<V>(path: &Path, keyring: &Keyring) -> FileReport
+where
+    V: DeserializeOwned,
+{
+    let mut report = FileReport::new(path);
+    if let Err(err) = check_wal::<V>(path, keyring, &mut report) {
+        report.problems.push(err.to_string());
+    }
+    report
+}
+
+fn check_wal<V>(path: &Path, keyring: &Keyring, report: &mut FileReport) -> Result<()>
+where
+    V: DeserializeOwned,
+{
+    let recovery = read_records(&fs::read(path)?)?;
+    if recovery.dropped_bytes > 0 {
+        report.problems.push(format!(
+            "{} bytes from {} on are torn or corrupt",
+            recovery.dropped_bytes, recovery.valid_len
+        ));
+    }
+
+    let log_decrypter = recovery.decrypter(keyring)?;
+    for (n, mut record) in recovery.records.into_iter().enumerate() {
+        let decrypter = record.decrypter(keyring, log_decrypter.as_ref())?;
+        let opened = match record.open(&decrypter) {
+            Ok((_, plaintext)) => decode_record::<V>(record.kind, &plaintext),
+            Err(err) => Err(err.to_string()),
+        };
+        match opened {
+            Ok(()) => report.entries += 1,
+            Err(err) => report.problems.push(format!(
+                "record {n} for {} does not open: {err}",
+                display_bytes(&record.key)
+            )),
+        }
+    }
+    Ok(())
+}
+
+fn decode_record<V>(kind: RecordKind, plaintext: &[u8]) -> Result<(), String>
+where
+    V: DeserializeOwned,
+{
+    if kind != RecordKind::Set {
+        return Ok(());
+    }
+    bincode::serde::decode_from_slice::<V, _>(plaintext, bincode::config::standard())
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{KvStore, Options};
+    use crate::testutil;
+    use crate::wal::WAL_VERSION;
+
+    const SECRET: Secret = Secret::Key([1; 32]);
+
+    // Eight entries in two segments and three more in the live log
+    fn store(dir: &Path) {
+        let store = KvStore::<Vec<u8>>::open(dir, Options::with_secret(SECRET)).unwrap();
+        for n in 0..8u8 {
+            store.put(&[b'k', n], vec![n; 40]).unwrap();
+        }
+        store.flush().unwrap();
+        for n in 8..11u8 {
+            store.put(&[b'k', n], vec![n; 40]).unwrap();
+        }
+    }
+
+    fn flip(path: &Path, offset: u64) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn footer(path: &Path) -> segment::Footer {
+        SegmentFile::parse_footer(&mut File::open(path).unwrap()).unwrap()
+    }
+
+    fn problems_in(dir: &Path) -> Vec<String> {
+        let report = verify_dir::<Vec<u8>>(dir, &SECRET).unwrap();
+        report
+            .files
+            .into_iter()
+            .flat_map(|file| file.problems)
+            .collect()
+    }
+
+    fn first_segment(dir: &Path) -> PathBuf {
+        segment_path(dir, list_segment_ids(dir).unwrap()[0])
+    }
+
+    #[test]
+    fn a_sound_store_verifies() {
+        let dir = testutil::temp_dir("verify");
+        store(&dir);
+        let report = verify_dir::<Vec<u8>>(&dir, &SECRET).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.failed(), 0);
+        let entries: usize = report.files.iter().map(|file| file.entries).sum();
+        assert_eq!(entries, 11);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_entry_that_does_not_authenticate_is_reported() {
+        let dir = testutil::temp_dir("verify");
+        store(&dir);
+        let path = first_segment(&dir);
+        // The last byte of the last entry, inside its tag
+        flip(&path, footer(&path).idx_offset - 1);
+
+        let report = verify_dir::<Vec<u8>>(&dir, &SECRET).unwrap();
+        assert_eq!(report.failed(), 1);
+        let problems = problems_in(&dir);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("does not open"), "{problems:?}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_broken_index_is_reported() {
+        let dir = testutil::temp_dir("verify");
+        store(&dir);
+        let path = first_segment(&dir);
+        // The low byte of the offset the first index key points at
+        let footer = footer(&path);
+        let first_key_len = {
+            let bytes = fs::read(&path).unwrap();
+            let start = footer.idx_offset as usize;
+            u32::from_be_bytes(bytes[start..start + 4].try_into().unwrap()) as u64
+        };
+        flip(&path, footer.idx_offset + 4 + first_key_len + 7);
+
+        let problems = problems_in(&dir);
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("which is not an entry")),
+            "{problems:?}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_footer_that_does_not_match_the_layout_is_reported() {
+        let dir = testutil::temp_dir("verify");
+        store(&dir);
+        let path = first_segment(&dir);
+        // The low byte of the index size
+        flip(&path, footer(&path).footer_offset + 15);
+
+        let problems = problems_in(&dir);
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("does not end at the footer")),
+            "{problems:?}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_log_and_a_record_that_does_not_open_are_reported() {
+        let dir = testutil::temp_dir("verify");
+        store(&dir);
+        let log = wal_path(&dir, *list_wal_ids(&dir).unwrap().last().unwrap());
+        let mut bytes = fs::read(&log).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        fs::write(&log, &bytes).unwrap();
+
+        let problems = problems_in(&dir);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("3 bytes"), "{problems:?}");
+        assert!(problems[0].contains("torn or corrupt"), "{problems:?}");
+
+        // A sound frame around a record whose ciphertext changed
+        let recovery = read_records(&bytes).unwrap();
+        let mut record = recovery.records[0].clone();
+        record.sealed[0] ^= 0xff;
+        let mut bytes = bytes[..recovery.valid_len as usize].to_vec();
+        bytes.extend_from_slice(&record.encode(WAL_VERSION));
+        fs::write(&log, &bytes).unwrap();
+        let problems = problems_in(&dir);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("does not open"), "{problems:?}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,854 @@
+use crate::encryption::{Decrypter, DefaultDecrypter, DefaultEncrypter};
+use crate::keyring::{FileKey, Keyring, WRAPPED_KEY_LEN};
+use crate::padding::unpad;
+use crate::segment::{FLAG_PADDED, FLAG_SEALED_KEY, entry_aad, unwrap_key};
+use anyhow::{Error, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, Weak},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Log layout: `EKVWAL` + u16 version, followed by records framed as
+// `len: u32 | crc32(len, payload): u32 | payload`. Version 2 adds a timestamp to every record,
+// version 3 an expiry. Version 4 puts the log's wrapped data key after the version and drops the
+// salt from the records.
+const WAL_MAGIC: &[u8; 6] = b"EKVWAL";
+pub const WAL_VERSION: u16 = 4;
+pub const WAL_HEADER_SIZE: usize = 8;
+const FRAME_HEADER_SIZE: usize = 8;
+// Set on the kind byte of records whose key field is a tag, with the key sealed in front of the
+// value. Works in logs of every version, which keep being appended to in their own format.
+const KIND_SEALED_KEY: u8 = 0x80;
+// Set on the kind byte of records whose sealed plaintext is padded
+const KIND_PADDED: u8 = 0x40;
+
+// The text log stores kept before binary WALs, one `SET <key> <sealed> <nonce> <salt>` line per
+// write with the last three base64 encoded
+pub const LEGACY_LOG: &str = "wal.log";
+
+// How far an acknowledged write has made it towards the disk when `append` returns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    // Every append is followed by `sync_data` before returning: an acknowledged write
+    // survives a power failure
+    #[default]
+    Always,
+    // Same guarantee as `Always`, but writers that append while a sync is in flight
+    // share the next `sync_data` instead of issuing one each
+    GroupCommit,
+    // A background thread syncs the log on the given interval: on power failure at most
+    // the writes acknowledged during the last interval are lost. The interval has to be
+    // above zero
+    Periodic(Duration),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalStats {
+    pub appended_bytes: u64,
+    pub synced_bytes: u64,
+    pub syncs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Set = 1,
+    Delete = 2,
+}
+
+impl TryFrom<u8> for RecordKind {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(RecordKind::Set),
+            2 => Ok(RecordKind::Delete),
+            _ => Err(Error::msg("unknown record kind")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    pub kind: RecordKind,
+    // Milliseconds since the unix epoch, zero for records from version 1 logs
+    pub timestamp: u64,
+    pub expires_at: Option<u64>,
+    pub key: Vec<u8>,
+    pub sealed_key: bool,
+    pub padded: bool,
+    pub nonce: [u8; 12],
+    // Zero in logs with a data key
+    pub salt: [u8; 16],
+    pub sealed: Vec<u8>,
+}
+
+impl WalRecord {
+    pub fn encode(&self, version: u16) -> Vec<u8> {
+        let mut payload =
+            Vec::with_capacity(1 + 16 + 4 + self.key.len() + 12 + 16 + self.sealed.len());
+        let mut kind = self.kind as u8;
+        if self.sealed_key {
+            kind |= KIND_SEALED_KEY;
+        }
+        if self.padded {
+            kind |= KIND_PADDED;
+        }
+        payload.push(kind);
+        if version >= 2 {
+            payload.extend_from_slice(&self.timestamp.to_be_bytes());
+        }
+        if version >= 3 {
+            payload.extend_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        }
+        payload.extend_from_slice(&(self.key.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&self.key);
+        payload.extend_from_slice(&self.nonce);
+        if version < 4 {
+            payload.extend_from_slice(&self.salt);
+        }
+        payload.extend_from_slice(&self.sealed);
+
+        let len_bytes = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&len_bytes);
+        frame.extend_from_slice(&frame_crc(&len_bytes, &payload).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn decode(mut payload: &[u8], version: u16) -> Result<Self> {
+        let mut kind = [0u8; 1];
+        payload.read_exact(&mut kind)?;
+
+        let mut timestamp_bytes = [0u8; 8];
+        if version >= 2 {
+            payload.read_exact(&mut timestamp_bytes)?;
+        }
+        let mut expires_bytes = [0u8; 8];
+        if version >= 3 {
+            payload.read_exact(&mut expires_bytes)?;
+        }
+
+        let mut key_len_bytes = [0u8; 4];
+        payload.read_exact(&mut key_len_bytes)?;
+        let mut key = vec![0; u32::from_be_bytes(key_len_bytes) as usize];
+        payload.read_exact(&mut key)?;
+
+        let mut nonce = [0u8; 12];
+        payload.read_exact(&mut nonce)?;
+        let mut salt = [0u8; 16];
+        if version < 4 {
+            payload.read_exact(&mut salt)?;
+        }
+
+        Ok(Self {
+            kind: RecordKind::try_from(kind[0] & !(KIND_SEALED_KEY | KIND_PADDED))?,
+            timestamp: u64::from_be_bytes(timestamp_bytes),
+            expires_at: Some(u64::from_be_bytes(expires_bytes)).filter(|expires| *expires != 0),
+            key,
+            sealed_key: kind[0] & KIND_SEALED_KEY != 0,
+            padded: kind[0] & KIND_PADDED != 0,
+            nonce,
+            salt,
+            sealed: payload.to_vec(),
+        })
+    }
+
+    // Same binding as a segment entry's, see `RawEntry::aad`
+    pub fn aad(&self) -> Vec<u8> {
+        let mut aad = entry_aad(&self.key, self.expires_at);
+        if self.sealed_key {
+            aad.push(FLAG_SEALED_KEY);
+        }
+        if self.padded {
+            aad.push(FLAG_PADDED);
+        }
+        aad
+    }
+
+    // Records of logs with a data key open with the log's decrypter, older ones name a salt
+    pub fn decrypter(
+        &self,
+        keyring: &Keyring,
+        log_decrypter: Option<&DefaultDecrypter>,
+    ) -> Result<DefaultDecrypter> {
+        match log_decrypter {
+            Some(decrypter) => Ok(decrypter.clone()),
+            None => keyring.decrypter(&FileKey::Salt(self.salt)),
+        }
+    }
+
+    // The key and the encoded value, empty for deletes
+    pub fn open(&mut self, decrypter: &DefaultDecrypter) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut aad = self.aad();
+        let mut plaintext: &[u8] = decrypter.decrypt(&mut self.sealed, self.nonce, &mut aad)?;
+        if self.padded {
+            plaintext = unpad(plaintext)?;
+        }
+        match self.sealed_key {
+            true => unwrap_key(plaintext),
+            false => Ok((self.key.clone(), plaintext.to_vec())),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Recovery {
+    // `None` for logs from before data keys
+    pub key: Option<FileKey>,
+    pub records: Vec<WalRecord>,
+    pub valid_len: u64,
+    pub dropped_bytes: u64,
+}
+
+impl Recovery {
+    pub fn decrypter(&self, keyring: &Keyring) -> Result<Option<DefaultDecrypter>> {
+        self.key
+            .as_ref()
+            .map(|key| keyring.decrypter(key))
+            .transpose()
+    }
+}
+
+// Reads records up to the first torn or corrupt frame, everything after it is reported as dropped
+pub fn read_records(bytes: &[u8]) -> Result<Recovery> {
+    let torn = Recovery {
+        dropped_bytes: bytes.len() as u64,
+        ..Default::default()
+    };
+    if bytes.len() < WAL_HEADER_SIZE {
+        return Ok(torn);
+    }
+    let version = parse_header(&bytes[0..WAL_HEADER_SIZE])?;
+    let Some(header) = bytes.get(..header_size(version)) else {
+        return Ok(torn);
+    };
+    let key = header_key(header)?;
+
+    let mut records = Vec::new();
+    let mut pos = header.len();
+    while let Some(frame) = bytes.get(pos..pos + FRAME_HEADER_SIZE) {
+        let len_bytes: [u8; 4] = frame[0..4].try_into()?;
+        let crc = u32::from_be_bytes(frame[4..8].try_into()?);
+        let payload_start = pos + FRAME_HEADER_SIZE;
+        let Some(payload) =
+            bytes.get(payload_start..payload_start + u32::from_be_bytes(len_bytes) as usize)
+        else {
+            break;
+        };
+        if frame_crc(&len_bytes, payload) != crc {
+            break;
+        }
+        let Ok(record) = WalRecord::decode(payload, version) else {
+            break;
+        };
+        records.push(record);
+        pos = payload_start + payload.len();
+    }
+
+    Ok(Recovery {
+        key,
+        records,
+        valid_len: pos as u64,
+        dropped_bytes: (bytes.len() - pos) as u64,
+    })
+}
+
+// Reads a text log up to the first line that does not parse, a write cut short leaves one at the
+// end. Its records name their salt and bind the bare key, like those of version 1 logs.
+pub fn read_legacy_log(bytes: &[u8]) -> Recovery {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+        let Some(record) = line
+            .strip_suffix(b"\n")
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .and_then(parse_legacy_line)
+        else {
+            break;
+        };
+        records.push(record);
+        pos += line.len();
+    }
+    Recovery {
+        key: None,
+        records,
+        valid_len: pos as u64,
+        dropped_bytes: (bytes.len() - pos) as u64,
+    }
+}
+
+fn parse_legacy_line(line: &str) -> Option<WalRecord> {
+    let ["SET", key, sealed, nonce, salt] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    Some(WalRecord {
+        kind: RecordKind::Set,
+        timestamp: 0,
+        expires_at: None,
+        key: key.as_bytes().to_vec(),
+        sealed_key: false,
+        padded: false,
+        nonce: BASE64.decode(nonce).ok()?.try_into().ok()?,
+        salt: BASE64.decode(salt).ok()?.try_into().ok()?,
+        sealed: BASE64.decode(sealed).ok()?,
+    })
+}
+
+// A whole log in the current format, for rewriting one in place of another
+pub fn encode_log(wrapped_key: &[u8; WRAPPED_KEY_LEN], records: &[WalRecord]) -> Vec<u8> {
+    let mut bytes = log_header(wrapped_key);
+    for record in records {
+        bytes.extend_from_slice(&record.encode(WAL_VERSION));
+    }
+    bytes
+}
+
+fn parse_header(header: &[u8]) -> Result<u16> {
+    if &header[0..6] != WAL_MAGIC {
+        return Err(Error::msg("wal: missing log header"));
+    }
+    let version = u16::from_be_bytes(header[6..8].try_into()?);
+    if version == 0 || version > WAL_VERSION {
+        return Err(Error::msg(format!(
+            "wal: unsupported log version {version}"
+        )));
+    }
+    Ok(version)
+}
+
+fn header_size(version: u16) -> usize {
+    match version {
+        4.. => WAL_HEADER_SIZE + WRAPPED_KEY_LEN,
+        _ => WAL_HEADER_SIZE,
+    }
+}
+
+fn header_key(header: &[u8]) -> Result<Option<FileKey>> {
+    Ok(match header.get(WAL_HEADER_SIZE..) {
+        Some(wrapped) if !wrapped.is_empty() => Some(FileKey::Wrapped(wrapped.try_into()?)),
+        _ => None,
+    })
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn frame_crc(len_bytes: &[u8; 4], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len_bytes);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+#[derive(Debug)]
+struct LogFile {
+    handle: File,
+    // Note: older logs keep being appended to in the format they were created with
+    version: u16,
+    written: u64,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    synced: u64,
+    syncs: u64,
+    syncing: bool,
+}
+
+// Each memtable logs to its own `wal_<id>.log`, ids grow with every frozen memtable
+#[derive(Debug)]
+pub struct Wal {
+    id: usize,
+    log: Mutex<LogFile>,
+    sync_state: Mutex<SyncState>,
+    synced_cv: Condvar,
+    durability: Durability,
+    path: PathBuf,
+    // The log's data key, `None` for logs from before data keys, which are only replayed
+    encrypter: Option<DefaultEncrypter>,
+}
+
+impl Wal {
+    pub fn open(
+        dir: &Path,
+        id: usize,
+        durability: Durability,
+        keyring: &Keyring,
+    ) -> Result<Arc<Self>> {
+        // Note: the sync thread would spin on a zero interval
+        if durability == Durability::Periodic(Duration::ZERO) {
+            return Err(Error::msg("wal: periodic sync interval must be above zero"));
+        }
+        let path = wal_path(dir, id);
+        let (handle, version, encrypter) = open_log(&path, keyring)?;
+        let wal = Arc::new(Self {
+            id,
+            log: Mutex::new(LogFile {
+                handle,
+                version,
+                written: 0,
+            }),
+            sync_state: Mutex::new(SyncState::default()),
+            synced_cv: Condvar::new(),
+            durability,
+            path,
+            encrypter,
+        });
+
+        if let Durability::Periodic(interval) = durability {
+            let weak = Arc::downgrade(&wal);
+            thread::spawn(move || run_periodic_sync(weak, interval));
+        }
+        Ok(wal)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn version(&self) -> u16 {
+        self.log.lock().expect("lock log file").version
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    // Note: stays valid across password changes, which only wrap the key again
+    pub fn encrypter(&self) -> Result<&DefaultEncrypter> {
+        self.encrypter
+            .as_ref()
+            .ok_or_else(|| Error::msg("wal: log from before data keys is not appended to"))
+    }
+
+    // Replays the current log and cuts off a torn tail so new appends land after the last good record
+    pub fn recover(&self) -> Result<Recovery> {
+        let log = self.log.lock().expect("lock log file");
+        let mut bytes = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut bytes)?;
+
+        let recovery = read_records(&bytes)?;
+        if recovery.dropped_bytes > 0 {
+            log.handle.set_len(recovery.valid_len)?;
+            log.handle.sync_data()?;
+        }
+        Ok(recovery)
+    }
+
+    pub fn append(&self, record: &WalRecord) -> Result<()> {
+        let mut log = self.log.lock().expect("lock log file");
+        let frame = record.encode(log.version);
+        log.handle.write_all(&frame)?;
+        log.written += frame.len() as u64;
+        let end = log.written;
+
+        match self.durability {
+            Durability::Always => {
+                log.handle.sync_data()?;
+                self.mark_synced(end);
+                Ok(())
+            }
+            Durability::GroupCommit => {
+                drop(log);
+                self.wait_synced(end)
+            }
+            Durability::Periodic(_) => Ok(()),
+        }
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        let log = self.log.lock().expect("lock log file");
+        if self.sync_state.lock().expect("lock sync state").synced >= log.written {
+            return Ok(());
+        }
+        log.handle.sync_data()?;
+        self.mark_synced(log.written);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> WalStats {
+        let appended_bytes = self.log.lock().expect("lock log file").written;
+        let state = self.sync_state.lock().expect("lock sync state");
+        WalStats {
+            appended_bytes,
+            synced_bytes: state.synced,
+            syncs: state.syncs,
+        }
+    }
+
+    // Called once the segment built from this log's memtable is durable
+    pub fn retire(&self, archive_dir: &Path) -> Result<()> {
+        fs::create_dir_all(archive_dir)?;
+        self.sync()?;
+
+        let archive_path = archive_dir.join(format!("wal_{}.log", self.id));
+        fs::rename(&self.path, &archive_path)?;
+        sync_dir(archive_dir)?;
+        if let Some(dir) = self.path.parent() {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    // Group commit: the first waiter becomes the leader and syncs everything written so far,
+    // the others wait for a sync that covers their record
+    fn wait_synced(&self, end: u64) -> Result<()> {
+        let mut state = self.sync_state.lock().expect("lock sync state");
+        loop {
+            if state.synced >= end {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced_cv.wait(state).expect("wait on sync");
+                continue;
+            }
+            state.syncing = true;
+            drop(state);
+
+            let synced = self.sync_unlocked();
+
+            state = self.sync_state.lock().expect("lock sync state");
+            state.syncing = false;
+            if let Ok(target) = synced {
+                state.synced = state.synced.max(target);
+                state.syncs += 1;
+            }
+            self.synced_cv.notify_all();
+            synced?;
+        }
+    }
+
+    // Syncs a duplicate handle so appenders are not blocked for the duration of the sync
+    fn sync_unlocked(&self) -> Result<u64> {
+        let (handle, target) = {
+            let log = self.log.lock().expect("lock log file");
+            (log.handle.try_clone()?, log.written)
+        };
+        handle.sync_data()?;
+        Ok(target)
+    }
+
+    fn mark_synced(&self, end: u64) {
+        let mut state = self.sync_state.lock().expect("lock sync state");
+        state.synced = state.synced.max(end);
+        state.syncs += 1;
+        self.synced_cv.notify_all();
+    }
+}
+
+fn run_periodic_sync(wal: Weak<Wal>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(wal) = wal.upgrade() else {
+            return;
+        };
+        if let Err(err) = wal.sync() {
+            eprintln!("{err}")
+        }
+    }
+}
+
+pub fn wal_path(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("wal_{id}.log"))
+}
+
+pub fn list_wal_ids(dir: &Path) -> Result<Vec<usize>> {
+    let mut ids: Vec<usize> = fs::read_dir(dir)?
+        .filter_map(|entry_result| {
+            let file_name = entry_result.ok()?.file_name();
+            file_name
+                .to_str()?
+                .strip_prefix("wal_")?
+                .strip_suffix(".log")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// Makes renames and newly created files in `dir` durable
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn open_log(path: &Path, keyring: &Keyring) -> Result<(File, u16, Option<DefaultEncrypter>)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let mut header = Vec::new();
+    (&file)
+        .take(header_size(WAL_VERSION) as u64)
+        .read_to_end(&mut header)?;
+
+    // Note: a log shorter than its header has no records yet, it starts over with a new key
+    if header.len() < WAL_HEADER_SIZE || header.len() < header_size(parse_header(&header)?) {
+        let (encrypter, wrapped_key) = keyring.new_data_key()?;
+        file.set_len(0)?;
+        file.write_all(&log_header(&wrapped_key))?;
+        file.sync_data()?;
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+        return Ok((file, WAL_VERSION, Some(encrypter)));
+    }
+
+    let version = parse_header(&header)?;
+    let encrypter = match header_key(&header[..header_size(version)])? {
+        Some(key) => Some(keyring.encrypter(&key)?),
+        None => None,
+    };
+    Ok((file, version, encrypter))
+}
+
+fn log_header(wrapped_key: &[u8; WRAPPED_KEY_LEN]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(header_size(WAL_VERSION));
+    header.extend_from_slice(WAL_MAGIC);
+    header.extend_from_slice(&WAL_VERSION.to_be_bytes());
+    header.extend_from_slice(wrapped_key);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Secret;
+    use crate::keyring::random_key;
+    use crate::testutil;
+
+    fn keyring() -> Keyring {
+        let master = random_key().expect("master key");
+        Keyring::new(Secret::Key(random_key().expect("key")), Some(&master))
+    }
+
+    fn record(n: u8) -> WalRecord {
+        WalRecord {
+            kind: RecordKind::Set,
+            timestamp: n as u64,
+            expires_at: None,
+            key: vec![n],
+            sealed_key: false,
+            padded: false,
+            nonce: [n; 12],
+            salt: [0; 16],
+            sealed: vec![n; 32],
+        }
+    }
+
+    fn reopened(dir: &Path, keyring: &Keyring) -> Vec<WalRecord> {
+        let wal = Wal::open(dir, 0, Durability::Always, keyring).expect("reopen wal");
+        wal.recover().expect("recover wal").records
+    }
+
+    #[test]
+    fn always_syncs_every_append() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let wal = Wal::open(&dir, 0, Durability::Always, &keyring).unwrap();
+        for n in 0..5 {
+            wal.append(&record(n)).unwrap();
+        }
+
+        let stats = wal.stats();
+        assert_eq!(stats.syncs, 5);
+        assert_eq!(stats.synced_bytes, stats.appended_bytes);
+        drop(wal);
+        assert_eq!(
+            reopened(&dir, &keyring),
+            (0..5).map(record).collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn group_commit_syncs_every_acknowledged_append() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let wal = Wal::open(&dir, 0, Durability::GroupCommit, &keyring).unwrap();
+        for n in 0..3 {
+            wal.append(&record(n)).unwrap();
+            let stats = wal.stats();
+            assert_eq!(stats.synced_bytes, stats.appended_bytes);
+        }
+        drop(wal);
+        assert_eq!(
+            reopened(&dir, &keyring),
+            (0..3).map(record).collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn group_commit_shares_one_sync_between_waiting_writers() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let wal = Wal::open(&dir, 0, Durability::GroupCommit, &keyring).unwrap();
+        let writers = 8;
+
+        // Note: a sync in flight makes every writer wait, they all append before it finishes
+        wal.sync_state.lock().unwrap().syncing = true;
+        let handles: Vec<_> = (0..writers)
+            .map(|n| {
+                let wal = Arc::clone(&wal);
+                thread::spawn(move || wal.append(&record(n)))
+            })
+            .collect();
+        let frame_len = record(0).encode(WAL_VERSION).len() as u64;
+        while wal.stats().appended_bytes < frame_len * writers as u64 {
+            thread::yield_now();
+        }
+        {
+            let mut state = wal.sync_state.lock().unwrap();
+            state.syncing = false;
+            wal.synced_cv.notify_all();
+        }
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        let stats = wal.stats();
+        assert_eq!(stats.syncs, 1);
+        assert_eq!(stats.synced_bytes, stats.appended_bytes);
+        drop(wal);
+        assert_eq!(reopened(&dir, &keyring).len(), writers as usize);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn periodic_syncs_in_the_background() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let interval = Duration::from_millis(10);
+        let wal = Wal::open(&dir, 0, Durability::Periodic(interval), &keyring).unwrap();
+        wal.append(&record(0)).unwrap();
+        wal.append(&record(1)).unwrap();
+
+        let appended = wal.stats().appended_bytes;
+        for _ in 0..500 {
+            if wal.stats().synced_bytes == appended {
+                break;
+            }
+            thread::sleep(interval);
+        }
+        let stats = wal.stats();
+        assert_eq!(stats.synced_bytes, appended);
+        assert!(stats.syncs >= 1);
+        drop(wal);
+        assert_eq!(reopened(&dir, &keyring), vec![record(0), record(1)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn periodic_rejects_a_zero_interval() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let err = Wal::open(&dir, 0, Durability::Periodic(Duration::ZERO), &keyring).unwrap_err();
+        assert!(err.to_string().contains("above zero"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The log after `count` records, with the offset each frame starts at
+    fn written_log(dir: &Path, keyring: &Keyring, count: u8) -> (PathBuf, Vec<usize>) {
+        let wal = Wal::open(dir, 0, Durability::Always, keyring).unwrap();
+        let mut starts = vec![header_size(WAL_VERSION)];
+        for n in 0..count {
+            wal.append(&record(n)).unwrap();
+            starts.push(starts[starts.len() - 1] + record(n).encode(WAL_VERSION).len());
+        }
+        (wal_path(dir, 0), starts)
+    }
+
+    #[test]
+    fn a_torn_last_record_is_dropped_and_cut_off() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let (path, starts) = written_log(&dir, &keyring, 5);
+        let len = fs::metadata(&path).unwrap().len();
+        assert_eq!(len, starts[5] as u64);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let wal = Wal::open(&dir, 0, Durability::Always, &keyring).unwrap();
+        let recovery = wal.recover().unwrap();
+        assert_eq!(recovery.records, (0..4).map(record).collect::<Vec<_>>());
+        assert_eq!(recovery.valid_len, starts[4] as u64);
+        assert_eq!(recovery.dropped_bytes, (starts[5] - starts[4] - 3) as u64);
+        assert_eq!(fs::metadata(&path).unwrap().len(), starts[4] as u64);
+
+        // Appends after the cut land where the torn record was
+        wal.append(&record(9)).unwrap();
+        drop(wal);
+        let mut expected: Vec<_> = (0..4).map(record).collect();
+        expected.push(record(9));
+        assert_eq!(reopened(&dir, &keyring), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_crc_mid_file_drops_everything_after_it() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let (path, starts) = written_log(&dir, &keyring, 5);
+        let mut bytes = fs::read(&path).unwrap();
+        // The CRC of the third record
+        bytes[starts[2] + 4] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let recovery = read_records(&bytes).unwrap();
+        assert_eq!(recovery.records, (0..2).map(record).collect::<Vec<_>>());
+        assert_eq!(recovery.valid_len, starts[2] as u64);
+        assert_eq!(recovery.dropped_bytes, (starts[5] - starts[2]) as u64);
+        assert_eq!(
+            reopened(&dir, &keyring),
+            (0..2).map(record).collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dropped_bytes_count_what_does_not_frame() {
+        let (dir, keyring) = (testutil::temp_dir("wal"), keyring());
+        let (path, starts) = written_log(&dir, &keyring, 2);
+        let mut bytes = fs::read(&path).unwrap();
+
+        let recovery = read_records(&bytes).unwrap();
+        assert_eq!((recovery.records.len(), recovery.dropped_bytes), (2, 0));
+
+        // A payload byte flipped fails the CRC just like the CRC itself
+        let mut flipped = bytes.clone();
+        flipped[starts[1] + 12] ^= 1;
+        let recovery = read_records(&flipped).unwrap();
+        assert_eq!(recovery.records.len(), 1);
+        assert_eq!(recovery.dropped_bytes, (starts[2] - starts[1]) as u64);
+
+        bytes.extend_from_slice(&[0xff; 7]);
+        let recovery = read_records(&bytes).unwrap();
+        assert_eq!((recovery.records.len(), recovery.dropped_bytes), (2, 7));
+
+        let recovery = read_records(&bytes[..starts[0] - 1]).unwrap();
+        assert!(recovery.records.is_empty());
+        assert_eq!(recovery.dropped_bytes, (starts[0] - 1) as u64);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const BIN: &str = env!("CARGO_BIN_EXE_enc-kv-store");
+
+// A fresh directory with a key file beside it, a key skips the slow password hashing
+fn setup(name: &str) -> (PathBuf, PathBuf) {
+    let root = std::env::temp_dir().join(format!("enc-kv-cli-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("data")).unwrap();
+    let key_file = root.join("key");
+    fs::write(&key_file, [7u8; 32]).unwrap();
+    (root, key_file)
+}
+
+fn cli(cwd: &Path, key_file: &Path, args: &[&str]) -> Output {
+    Command::new(BIN)
+        .current_dir(cwd)
+        .env_remove("ENC_KV_PASSWORD")
+        .env_remove("ENC_KV_KEY_FILE")
+        .arg("--key-file")
+        .arg(key_file)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn exit_codes() {
+    let (root, key_file) = setup("exit");
+    let data = root.join("data");
+
+    let put = cli(&data, &key_file, &["put", "k", "v"]);
+    assert_eq!(put.status.code(), Some(0));
+    let get = cli(&data, &key_file, &["get", "k"]);
+    assert_eq!(get.status.code(), Some(0));
+    assert_eq!(stdout(&get), "v\n");
+
+    // Not found is a failure, not an error
+    let missing = cli(&data, &key_file, &["get", "nope"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(missing.stdout.is_empty());
+
+    // The wrong key is an error
+    let other_key = root.join("other");
+    fs::write(&other_key, [8u8; 32]).unwrap();
+    let wrong = cli(&data, &other_key, &["get", "k"]);
+    assert_eq!(wrong.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&wrong.stderr).starts_with("error:"));
+
+    // As is a key that is not hex under --hex-keys
+    let bad_hex = cli(&data, &key_file, &["--hex-keys", "get", "zz"]);
+    assert_eq!(bad_hex.status.code(), Some(2));
+
+    assert_eq!(cli(&data, &key_file, &["verify"]).status.code(), Some(0));
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn json_and_hex_keys() {
+    let (root, key_file) = setup("json");
+    let data = root.join("data");
+
+    let put = cli(
+        &data,
+        &key_file,
+        &["--json", "--hex-keys", "put", "00ff", "binary"],
+    );
+    assert_eq!(stdout(&put).trim(), r#"{"ok":true}"#);
+    assert_eq!(
+        cli(&data, &key_file, &["put", "text", "plain"])
+            .status
+            .code(),
+        Some(0)
+    );
+
+    let get = cli(&data, &key_file, &["--json", "--hex-keys", "get", "00ff"]);
+    let entry: serde_json::Value = serde_json::from_str(&stdout(&get)).unwrap();
+    assert_eq!(
+        entry,
+        serde_json::json!({ "key_base64": "AP8=", "value": "binary" })
+    );
+
+    let missing = cli(&data, &key_file, &["--json", "get", "nope"]);
+    assert_eq!(missing.status.code(), Some(1));
+    let entry: serde_json::Value = serde_json::from_str(&stdout(&missing)).unwrap();
+    assert_eq!(entry, serde_json::json!({ "key": "nope", "value": null }));
+
+    let scan = cli(&data, &key_file, &["--json", "scan"]);
+    let entries: serde_json::Value = serde_json::from_str(&stdout(&scan)).unwrap();
+    assert_eq!(
+        entries,
+        serde_json::json!([
+            { "key_base64": "AP8=", "value": "binary" },
+            { "key": "text", "value": "plain" },
+        ])
+    );
+
+    // Without --json, keys that are not text come out as hex literals
+    let scan = cli(&data, &key_file, &["scan"]);
+    assert_eq!(stdout(&scan), "0x00ff\tbinary\ntext\tplain\n");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn dir_defaults_to_the_current_directory() {
+    let (root, key_file) = setup("dir");
+    let data = root.join("data");
+
+    assert_eq!(
+        cli(&root, &key_file, &["--dir", "data", "put", "k", "v"])
+            .status
+            .code(),
+        Some(0)
+    );
+    assert!(fs::read_dir(&data).unwrap().next().is_some());
+
+    // The same store opened from inside it without --dir
+    assert_eq!(stdout(&cli(&data, &key_file, &["get", "k"])), "v\n");
+    // And nothing of it where the first command ran
+    assert_eq!(cli(&root, &key_file, &["get", "k"]).status.code(), Some(1));
+    fs::remove_dir_all(root).unwrap();
+}